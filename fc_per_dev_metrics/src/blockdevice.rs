@@ -0,0 +1,91 @@
+use crate::metric_group;
+use crate::metrics::{DeviceHandle, DeviceMetrics, MetricsError, SharedIncMetric, METRICS};
+use serde::Serialize;
+
+///////////////////////////////////////////////////////////////////////////////
+/////////////////////////////////// METRICS ///////////////////////////////////
+///////////////////////////////////////////////////////////////////////////////
+
+metric_group! {
+    /// Block Device associated metrics.
+    #[derive(Debug, Default, Serialize)]
+    pub struct BlockDeviceMetrics {
+        /// Number of times when activate failed on a block device.
+        pub activate_fails: SharedIncMetric,
+        /// Number of times when interacting with the space config of a block device failed.
+        pub cfg_fails: SharedIncMetric,
+        /// No available buffer for the block queue.
+        pub no_avail_buffer: SharedIncMetric,
+        /// Number of times when handling events on a block device failed.
+        pub event_fails: SharedIncMetric,
+        /// Number of failures in executing a request on a block device.
+        pub execute_fails: SharedIncMetric,
+        /// Number of invalid requests received for this block device.
+        pub invalid_reqs_count: SharedIncMetric,
+        /// Number of flushes operation triggered on this block device.
+        pub flush_count: SharedIncMetric,
+        /// Number of events triggerd on the queue of this block device.
+        pub queue_event_count: SharedIncMetric,
+        /// Number of events ratelimiter-related.
+        pub rate_limiter_event_count: SharedIncMetric,
+        /// Number of update operation triggered on this block device.
+        pub update_count: SharedIncMetric,
+        /// Number of failures while doing update on this block device.
+        pub update_fails: SharedIncMetric,
+        /// Number of bytes read by this block device.
+        #[unit = Bytes]
+        pub read_bytes: SharedIncMetric,
+        /// Number of bytes written by this block device.
+        #[unit = Bytes]
+        pub write_bytes: SharedIncMetric,
+        /// Number of successful read operations.
+        pub read_count: SharedIncMetric,
+        /// Number of successful write operations.
+        pub write_count: SharedIncMetric,
+        /// Number of rate limiter throttling events.
+        pub rate_limiter_throttled_events: SharedIncMetric,
+    }
+}
+
+impl DeviceMetrics for BlockDeviceMetrics {
+    const PREFIX: &'static str = "block";
+}
+
+/// Metrics handle of a block device.
+pub type Block = DeviceHandle<BlockDeviceMetrics>;
+
+impl Block {
+    pub fn new(id: String) -> Result<Block, MetricsError> {
+        DeviceHandle::register(&METRICS.block, id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::IncMetric;
+
+    #[test]
+    fn test_block_device_lifecycle() {
+        let vda = Block::new("vda".to_string()).unwrap();
+        vda.metrics.read_bytes.add(512);
+        vda.metrics.read_count.inc();
+        let json = serde_json::to_value(&METRICS.block).unwrap();
+        assert_eq!(json["block_vda"]["read_bytes"], 512);
+        assert_eq!(json["block_vda"]["read_count"], 1);
+
+        // The id stays taken while the device is present.
+        assert!(matches!(
+            Block::new("vda".to_string()),
+            Err(MetricsError::DuplicateDevice(_))
+        ));
+
+        // Once dropped, the device can be plugged back in and carries on
+        // with its unflushed metrics.
+        drop(vda);
+        let vda = Block::new("vda".to_string()).unwrap();
+        vda.metrics.read_count.inc();
+        let json = serde_json::to_value(&METRICS.block).unwrap();
+        assert_eq!(json["block_vda"]["read_count"], 2);
+    }
+}
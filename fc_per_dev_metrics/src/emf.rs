@@ -0,0 +1,451 @@
+use crate::exporter::MetricsExporter;
+use crate::metrics::MetricsError;
+use crate::snapshot::{GroupScope, MetricValue, MetricsSnapshot, Temporality};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io::Write;
+
+use serde::{Serialize, Serializer, Deserialize, ser::SerializeMap};
+
+#[derive(Debug, Deserialize)]
+pub struct EMFMetrics{
+    inner: BTreeMap<String,u64>,
+}
+
+/// Settings shared by every EMF document, set once when the exporter is
+/// created.
+#[derive(Debug, Clone)]
+pub struct EmfConfig {
+    namespace: String,
+    dimensions: Vec<Vec<String>>,
+    properties: BTreeMap<String, serde_json::Value>,
+    device_dimension: bool,
+    temporality: Temporality,
+}
+
+impl Default for EmfConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EmfConfig {
+    /// Creates a configuration using the `Firecracker` namespace, without
+    /// dimensions nor properties.
+    pub fn new() -> Self {
+        Self {
+            namespace: "Firecracker".to_string(),
+            dimensions: Vec::new(),
+            properties: BTreeMap::new(),
+            device_dimension: false,
+            temporality: Temporality::Delta,
+        }
+    }
+
+    /// Sets the CloudWatch namespace the metrics are published under.
+    pub fn cloudwatch_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = namespace.into();
+        self
+    }
+
+    /// Adds a dimension set, e.g. `["Sandbox", "Host"]`. Every dimension needs
+    /// a value set through `set_property`.
+    pub fn with_dimensions<I, D>(mut self, dimensions: I) -> Self
+    where
+        I: IntoIterator<Item = D>,
+        D: Into<String>,
+    {
+        self.dimensions
+            .push(dimensions.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Sets a property carried by every document, e.g. the instance id or the
+    /// region. Properties also hold the values of the dimensions.
+    pub fn set_property(mut self, name: impl Into<String>, value: impl Into<serde_json::Value>) -> Self {
+        self.properties.insert(name.into(), value.into());
+        self
+    }
+
+    /// Emits one record per device (`net`, `net_eth0`, ...) where metrics are
+    /// named after the field only and the device is the value of the `Device`
+    /// dimension, which is added to every dimension set, so that no property
+    /// can be named `Device` then. Groups which are not per device
+    /// (`metrics`, `signals`) get one record each, with `<group>.<field>`
+    /// names and the configured dimensions only.
+    /// By default every metric is named `<device>.<field>` within a single
+    /// record.
+    pub fn with_device_dimension(mut self) -> Self {
+        self.device_dimension = true;
+        self
+    }
+
+    /// Reports the counters with `temporality`, which defaults to the deltas
+    /// since the previous flush.
+    pub fn with_temporality(mut self, temporality: Temporality) -> Self {
+        self.temporality = temporality;
+        self
+    }
+
+    fn validate(&self) -> Result<(), MetricsError> {
+        if self.device_dimension && self.properties.contains_key(DEVICE_DIMENSION) {
+            return Err(MetricsError::Emf(format!(
+                "property {} is reserved for the device dimension",
+                DEVICE_DIMENSION
+            )));
+        }
+        match self
+            .dimensions
+            .iter()
+            .flatten()
+            .find(|dim| !self.properties.contains_key(*dim))
+        {
+            Some(dim) => Err(MetricsError::Emf(format!(
+                "dimension {} has no value set as property",
+                dim
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Maximum number of metrics CloudWatch accepts in a single EMF directive.
+pub const EMF_MAX_METRICS: usize = 100;
+
+/// Name of the dimension holding the device in `EmfConfig::with_device_dimension` mode.
+/// Its value is set per document, so the name is reserved and may not be used
+/// by `EmfConfig::set_property` in that mode.
+pub const DEVICE_DIMENSION: &str = "Device";
+
+/// Renders a snapshot as CloudWatch Embedded Metric Format documents, each
+/// holding at most `EMF_MAX_METRICS` metrics.
+pub fn emf_documents(config: &EmfConfig, snapshot: &MetricsSnapshot) -> Result<Vec<String>, MetricsError> {
+    #[derive(Debug, Serialize,Deserialize)]
+    struct Metric{
+        #[serde(rename = "Name")]
+        name: String,
+        #[serde(rename = "Unit")]
+        unit: String,
+    }
+    #[derive(Debug, Serialize,Deserialize)]
+    struct MetricDirective{
+        #[serde(rename = "Namespace")]
+        namespace: String,
+        #[serde(rename = "Dimensions")]
+        dimensions: Vec<Vec<String>>,
+        #[serde(rename = "Metrics")]
+        metrics: Vec<Metric>,
+        // Metrics: Vec<BTreeMap<String,String>>,
+    }
+    #[derive(Debug, Serialize,Deserialize)]
+    struct MetricDirectiveObj{
+        #[serde(rename = "Timestamp")]
+        timestamp: u64,
+        #[serde(rename = "CloudWatchMetrics")]
+        cloud_watch_metrics: Vec<MetricDirective>,
+    }
+
+    mod as_emf_metrics{
+        use super::*;
+        pub fn serialize<S>(metrics: &[EMFMetrics], serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+            {
+                let mut seq = serializer.serialize_map(Some(metrics.len()))?;
+                for metric in metrics.iter() {
+                    for (key,value) in metric.inner.iter(){
+                        seq.serialize_entry(key,value)?;
+                    }
+                }
+                seq.end()
+            }
+    }
+    #[derive(Debug, Serialize)]
+    struct EmfStruct {
+        #[serde(flatten)]
+        aws: BTreeMap<String,MetricDirectiveObj>,
+        #[serde(flatten)]
+        properties: BTreeMap<String,serde_json::Value>,
+        #[serde(flatten, with = "as_emf_metrics")]
+        metrics: Vec<EMFMetrics>,
+    }
+    // Each record gets its own documents: a single one holding every metric
+    // under a dotted name, or one per device with the device as dimension.
+    type Record<'a> = (Option<&'a str>, Vec<(String, &'a MetricValue)>);
+    let records: Vec<Record> = if config.device_dimension {
+        snapshot
+            .groups
+            .iter()
+            .map(|group| {
+                let device = match group.scope {
+                    GroupScope::Single => None,
+                    GroupScope::Aggregate | GroupScope::Device(_) => Some(group.name.as_str()),
+                };
+                let metrics = group
+                    .values
+                    .iter()
+                    .map(|metric| match device {
+                        Some(_) => (metric.field.name.to_string(), metric),
+                        None => (format!("{}.{}", group.name, metric.field.name), metric),
+                    })
+                    .collect();
+                (device, metrics)
+            })
+            .collect()
+    } else {
+        let metrics = snapshot
+            .groups
+            .iter()
+            .flat_map(|group| {
+                group.values.iter().map(move |metric| {
+                    (format!("{}.{}", group.name, metric.field.name), metric)
+                })
+            })
+            .collect();
+        vec![(None, metrics)]
+    };
+
+    // Every chunk is a standalone document repeating the shared properties,
+    // dimensions and timestamp.
+    let mut documents = Vec::new();
+    for (device, metrics) in records.iter() {
+        let mut properties = config.properties.clone();
+        let mut dimensions = config.dimensions.clone();
+        if let Some(device) = device {
+            properties.insert(DEVICE_DIMENSION.to_string(), (*device).into());
+            if dimensions.is_empty() {
+                dimensions.push(Vec::new());
+            }
+            for set in dimensions.iter_mut() {
+                set.push(DEVICE_DIMENSION.to_string());
+            }
+        }
+        for chunk in metrics.chunks(EMF_MAX_METRICS) {
+            let mut directive = MetricDirective{
+                namespace: config.namespace.clone(),
+                dimensions: dimensions.clone(),
+                metrics: Vec::new(),
+            };
+            let mut final_emf = EmfStruct{
+                aws: BTreeMap::new(),
+                properties: properties.clone(),
+                metrics: Vec::new(),
+            };
+            for (name, metric) in chunk.iter() {
+                let emfmetrics = EMFMetrics{
+                    inner: BTreeMap::from([(name.clone(),metric.get(config.temporality))])
+                };
+                final_emf.metrics.push(emfmetrics);
+                directive.metrics.push(
+                    Metric{
+                        name: name.clone(),
+                        unit: metric.field.unit().as_emf().to_string(),
+                    }
+                );
+            }
+            final_emf.aws.insert("_aws".to_string(),
+                MetricDirectiveObj{
+                    timestamp: snapshot.utc_timestamp_ms,
+                    cloud_watch_metrics: vec![directive],
+                }
+            );
+            documents.push(
+                serde_json::to_string(&final_emf).map_err(|err| MetricsError::Serde(err.to_string()))?,
+            );
+        }
+    }
+    Ok(documents)
+}
+
+/// Exports the metrics in the CloudWatch Embedded Metric Format.
+/// Each document is written on a single line, as expected by the CloudWatch
+/// agent when `dest` is a file, FIFO or socket it reads from.
+#[derive(Debug)]
+pub struct EmfExporter<W> {
+    dest: W,
+    config: EmfConfig,
+}
+
+impl<W: Write + Send + Debug> EmfExporter<W> {
+    /// Fails if a dimension of `config` has no value.
+    pub fn new(dest: W, config: EmfConfig) -> Result<Self, MetricsError> {
+        config.validate()?;
+        Ok(Self { dest, config })
+    }
+}
+
+impl<W: Write + Send + Debug> MetricsExporter for EmfExporter<W> {
+    fn export(&mut self, snapshot: &MetricsSnapshot) -> Result<usize, MetricsError> {
+        let mut written = 0;
+        for document in emf_documents(&self.config, snapshot)? {
+            writeln!(self.dest, "{}", document).map_err(|err| MetricsError::Emf(err.to_string()))?;
+            written += document.len() + 1;
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{FirecrackerMetrics, IncMetric};
+    use crate::snapshot::SnapshotMetrics;
+
+    #[derive(Debug)]
+    struct BrokenPipe;
+    impl Write for BrokenPipe {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_emf_exporter() {
+        let metrics = FirecrackerMetrics::new();
+        metrics.net.register("eth0").unwrap().rx_bytes_count.add(10);
+        let snapshot = metrics.snapshot();
+
+        let mut exporter = EmfExporter::new(Vec::new(), EmfConfig::new()).unwrap();
+        exporter.export(&snapshot).unwrap();
+        let out = String::from_utf8(exporter.dest).unwrap();
+        let emf: serde_json::Value = serde_json::from_str(out.lines().next().unwrap()).unwrap();
+        assert_eq!(emf["net_eth0.rx_bytes_count"], 10);
+        assert_eq!(emf["_aws"]["Timestamp"], snapshot.utc_timestamp_ms);
+
+        let err = EmfExporter::new(BrokenPipe, EmfConfig::new())
+            .unwrap()
+            .export(&snapshot)
+            .unwrap_err();
+        assert!(matches!(err, MetricsError::Emf(_)));
+    }
+
+    #[test]
+    fn test_emf_temporality() {
+        let metrics = FirecrackerMetrics::new();
+        let eth0 = metrics.net.register("eth0").unwrap();
+        eth0.rx_count.add(3);
+        let _ = metrics.snapshot();
+        eth0.rx_count.add(4);
+        let snapshot = metrics.snapshot();
+
+        for (temporality, expected) in [(Temporality::Delta, 4), (Temporality::Cumulative, 7)] {
+            let config = EmfConfig::new().with_temporality(temporality);
+            let documents = emf_documents(&config, &snapshot).unwrap();
+            let emf: serde_json::Value = serde_json::from_str(&documents[0]).unwrap();
+            assert_eq!(emf["net_eth0.rx_count"], expected);
+        }
+    }
+
+    #[test]
+    fn test_emf_chunks() {
+        let metrics = FirecrackerMetrics::new();
+        let _devices: Vec<_> = (0..4).map(|i| metrics.net.register(&format!("eth{}", i)).unwrap()).collect();
+        let snapshot = metrics.snapshot();
+        let total: usize = snapshot.groups.iter().map(|g| g.values.len()).sum();
+        assert!(total > EMF_MAX_METRICS);
+
+        let config = EmfConfig::new()
+            .with_dimensions(["Sandbox"])
+            .set_property("Sandbox", "sb-42");
+        let documents = emf_documents(&config, &snapshot).unwrap();
+        assert_eq!(documents.len(), total.div_ceil(EMF_MAX_METRICS));
+
+        let mut seen = 0;
+        for document in documents.iter() {
+            let emf: serde_json::Value = serde_json::from_str(document).unwrap();
+            let directive = &emf["_aws"]["CloudWatchMetrics"][0];
+            let names = directive["Metrics"].as_array().unwrap();
+            assert!(names.len() <= EMF_MAX_METRICS);
+            assert_eq!(directive["Dimensions"], serde_json::json!([["Sandbox"]]));
+            assert_eq!(emf["Sandbox"], "sb-42");
+            assert_eq!(emf["_aws"]["Timestamp"], snapshot.utc_timestamp_ms);
+            for name in names {
+                assert!(emf.get(name["Name"].as_str().unwrap()).is_some());
+            }
+            seen += names.len();
+        }
+        assert_eq!(seen, total);
+    }
+
+    #[test]
+    fn test_emf_device_dimension() {
+        let metrics = FirecrackerMetrics::new();
+        let eth0 = metrics.net.register("eth0").unwrap();
+        eth0.rx_bytes_count.add(10);
+        let snapshot = metrics.snapshot();
+
+        let config = EmfConfig::new()
+            .with_dimensions(["Sandbox"])
+            .set_property("Sandbox", "sb-42")
+            .with_device_dimension();
+        let documents: Vec<serde_json::Value> = emf_documents(&config, &snapshot)
+            .unwrap()
+            .iter()
+            .map(|doc| serde_json::from_str(doc).unwrap())
+            .collect();
+        assert_eq!(documents.len(), snapshot.groups.len());
+
+        let eth0 = documents.iter().find(|emf| emf["Device"] == "net_eth0").unwrap();
+        let net = documents.iter().find(|emf| emf["Device"] == "net").unwrap();
+        for emf in [eth0, net] {
+            let directive = &emf["_aws"]["CloudWatchMetrics"][0];
+            assert_eq!(directive["Dimensions"], serde_json::json!([["Sandbox", "Device"]]));
+            assert_eq!(emf["rx_bytes_count"], 10);
+            assert_eq!(emf["Sandbox"], "sb-42");
+            assert!(directive["Metrics"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|m| m["Name"] == "rx_bytes_count" && m["Unit"] == "Bytes"));
+        }
+
+        let signals = documents
+            .iter()
+            .find(|emf| emf.get("signals.fatal_signal").is_some())
+            .unwrap();
+        assert!(signals.get("Device").is_none());
+        assert_eq!(
+            signals["_aws"]["CloudWatchMetrics"][0]["Dimensions"],
+            serde_json::json!([["Sandbox"]])
+        );
+    }
+
+    #[test]
+    fn test_emf_config() {
+        let config = EmfConfig::new()
+            .cloudwatch_namespace("Fleet")
+            .with_dimensions(["Sandbox"])
+            .with_dimensions(["Sandbox", "Host"])
+            .set_property("Sandbox", "sb-42")
+            .set_property("Host", "host-1")
+            .set_property("Region", "eu-west-1");
+        let documents = emf_documents(&config, &FirecrackerMetrics::new().snapshot()).unwrap();
+        let emf: serde_json::Value = serde_json::from_str(&documents[0]).unwrap();
+        let directive = &emf["_aws"]["CloudWatchMetrics"][0];
+        assert_eq!(directive["Namespace"], "Fleet");
+        assert_eq!(directive["Dimensions"], serde_json::json!([["Sandbox"], ["Sandbox", "Host"]]));
+        assert_eq!(emf["Sandbox"], "sb-42");
+        assert_eq!(emf["Host"], "host-1");
+        assert_eq!(emf["Region"], "eu-west-1");
+
+        let missing = EmfConfig::new().with_dimensions(["Sandbox"]);
+        assert!(matches!(
+            EmfExporter::new(Vec::new(), missing),
+            Err(MetricsError::Emf(_))
+        ));
+
+        // The device would overwrite the property.
+        let reserved = EmfConfig::new()
+            .set_property(DEVICE_DIMENSION, "eth0")
+            .with_device_dimension();
+        assert!(matches!(
+            EmfExporter::new(Vec::new(), reserved),
+            Err(MetricsError::Emf(_))
+        ));
+        let without_device_dimension = EmfConfig::new().set_property(DEVICE_DIMENSION, "eth0");
+        assert!(EmfExporter::new(Vec::new(), without_device_dimension).is_ok());
+    }
+}
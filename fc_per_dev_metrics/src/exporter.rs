@@ -0,0 +1,69 @@
+use crate::metrics::MetricsError;
+use crate::snapshot::{MetricsSnapshot, Temporality};
+use std::fmt::Debug;
+use std::io::Write;
+
+/// Sink the metrics get flushed to on every `Metrics::write`.
+/// Every exporter renders the same snapshot in its own format, so adding a
+/// backend does not require touching `Metrics::write`.
+pub trait MetricsExporter: Send + Debug {
+    /// Renders `snapshot` to the sink and returns the number of bytes written.
+    fn export(&mut self, snapshot: &MetricsSnapshot) -> Result<usize, MetricsError>;
+}
+
+/// Layout of the Firecracker JSON metrics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JsonFormat {
+    /// One record per line (JSON Lines), suited to files, FIFOs and
+    /// line-oriented consumers.
+    #[default]
+    Compact,
+    /// Indented records spanning several lines, for humans.
+    Pretty,
+}
+
+/// Settings of the Firecracker JSON metrics. The default writes the deltas
+/// since the previous flush, one compact record per line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JsonOptions {
+    pub format: JsonFormat,
+    pub temporality: Temporality,
+}
+
+/// Exports the metrics in the Firecracker JSON format.
+#[derive(Debug)]
+pub struct FcJsonExporter<W> {
+    pub(crate) dest: W,
+    options: JsonOptions,
+}
+
+impl<W: Write + Send + Debug> FcJsonExporter<W> {
+    /// Creates an exporter writing one compact record of deltas per line.
+    pub fn new(dest: W) -> Self {
+        Self::with_options(dest, JsonOptions::default())
+    }
+
+    pub fn with_options(dest: W, options: JsonOptions) -> Self {
+        Self { dest, options }
+    }
+}
+
+impl<W: Write + Send + Debug> MetricsExporter for FcJsonExporter<W> {
+    fn export(&mut self, snapshot: &MetricsSnapshot) -> Result<usize, MetricsError> {
+        let snapshot = snapshot.with_temporality(self.options.temporality);
+        let msg = match self.options.format {
+            JsonFormat::Compact => serde_json::to_string(&snapshot),
+            JsonFormat::Pretty => serde_json::to_string_pretty(&snapshot),
+        }
+        .map_err(|err| MetricsError::Serde(err.to_string()))?;
+        let buf = format!("{msg}\n",);
+        // No need to explicitly call flush because the underlying LineWriter
+        // flushes automatically whenever a newline is
+        // detected (and we always end with a newline the
+        // current write).
+        self.dest
+            .write_all(buf.as_bytes())
+            .map_err(MetricsError::Write)
+            .map(|_| buf.len())
+    }
+}
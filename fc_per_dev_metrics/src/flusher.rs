@@ -0,0 +1,204 @@
+use crate::metrics::{get_time_ns, ClockType, Metrics};
+use crate::snapshot::SnapshotMetrics;
+use std::fmt::Debug;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Set from the signal handler installed by `PeriodicFlusher::start_with_flush_signal`.
+static FLUSH_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How often the flusher checks for a flush requested through a signal.
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+extern "C" fn request_flush(_: libc::c_int) {
+    // Storing to an atomic is async-signal-safe; the flush itself happens on
+    // the flusher thread.
+    FLUSH_REQUESTED.store(true, Ordering::Release);
+}
+
+/// Background thread calling `Metrics::write` every `interval`.
+/// Flush deadlines are computed from `ClockType::Monotonic` relative to the
+/// start of the flusher, so a slow flush does not shift the following ones,
+/// and intervals missed altogether are skipped instead of flushed in a burst.
+/// Metrics get flushed one last time when the flusher is stopped or dropped.
+#[derive(Debug)]
+pub struct PeriodicFlusher {
+    /// Set to `true` to stop the flusher thread.
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PeriodicFlusher {
+    /// Starts flushing `metrics` every `interval`.
+    pub fn start<T, M>(metrics: &'static Metrics<T, M>, interval: Duration) -> std::io::Result<Self>
+    where
+        T: SnapshotMetrics + Debug + Sync,
+        M: Write + Send + Debug,
+    {
+        Self::spawn(metrics, interval, false)
+    }
+
+    /// Same as `start`, but `signum` (e.g. `libc::SIGUSR1`) additionally
+    /// triggers a flush as soon as possible.
+    pub fn start_with_flush_signal<T, M>(
+        metrics: &'static Metrics<T, M>,
+        interval: Duration,
+        signum: libc::c_int,
+    ) -> std::io::Result<Self>
+    where
+        T: SnapshotMetrics + Debug + Sync,
+        M: Write + Send + Debug,
+    {
+        // SAFETY: `sigaction` is zero-initializable and `request_flush` is
+        // async-signal-safe.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = request_flush as extern "C" fn(libc::c_int) as usize;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signum, &action, std::ptr::null_mut()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Self::spawn(metrics, interval, true)
+    }
+
+    fn spawn<T, M>(
+        metrics: &'static Metrics<T, M>,
+        interval: Duration,
+        on_signal: bool,
+    ) -> std::io::Result<Self>
+    where
+        T: SnapshotMetrics + Debug + Sync,
+        M: Write + Send + Debug,
+    {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = Arc::clone(&stop);
+        let interval_ns = u64::try_from(interval.as_nanos()).unwrap_or(u64::MAX).max(1);
+
+        let thread = std::thread::Builder::new()
+            .name("metrics-flusher".to_string())
+            .spawn(move || {
+                let (lock, cvar) = &*thread_stop;
+                let start = get_time_ns(ClockType::Monotonic);
+                let mut deadline = start.saturating_add(interval_ns);
+                let mut stopped = lock.lock().unwrap_or_else(PoisonError::into_inner);
+                while !*stopped {
+                    let now = get_time_ns(ClockType::Monotonic);
+                    if now >= deadline {
+                        // Errors are accounted for in the metrics system
+                        // metrics, there is nobody to report them to here.
+                        let _ = metrics.write();
+                        let elapsed = get_time_ns(ClockType::Monotonic) - start;
+                        deadline = start.saturating_add((elapsed / interval_ns + 1) * interval_ns);
+                        continue;
+                    }
+                    if on_signal && FLUSH_REQUESTED.swap(false, Ordering::AcqRel) {
+                        let _ = metrics.write();
+                        continue;
+                    }
+                    let mut timeout = Duration::from_nanos(deadline - now);
+                    if on_signal {
+                        timeout = timeout.min(SIGNAL_POLL_INTERVAL);
+                    }
+                    stopped = cvar
+                        .wait_timeout(stopped, timeout)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                }
+                drop(stopped);
+                let _ = metrics.write();
+            })?;
+
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Stops the flusher after a final flush.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let (lock, cvar) = &*self.stop;
+            *lock.lock().unwrap_or_else(PoisonError::into_inner) = true;
+            cvar.notify_one();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for PeriodicFlusher {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{leaked_metrics, IncMetric};
+    use std::sync::{Arc, Mutex};
+
+    /// Destination shared with the test so that flushes can be counted.
+    #[derive(Debug, Default, Clone)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    impl SharedBuf {
+        fn flushes(&self) -> Vec<serde_json::Value> {
+            serde_json::Deserializer::from_slice(&self.0.lock().unwrap())
+                .into_iter()
+                .map(Result::unwrap)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_periodic_flush() {
+        let dest = SharedBuf::default();
+        let m = leaked_metrics();
+        m.init(dest.clone()).unwrap();
+        let eth0 = m.net.register("eth0").unwrap();
+
+        let flusher = PeriodicFlusher::start(m, Duration::from_millis(10)).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        eth0.rx_count.add(5);
+        flusher.stop();
+
+        let flushes = dest.flushes();
+        assert!(flushes.len() >= 3, "{} flushes", flushes.len());
+        // The increment made right before stopping lands in the final flush.
+        assert_eq!(flushes.last().unwrap()["net_eth0"]["rx_count"], 5);
+    }
+
+    #[test]
+    fn test_flush_on_signal() {
+        let dest = SharedBuf::default();
+        let m = leaked_metrics();
+        m.init(dest.clone()).unwrap();
+
+        let flusher =
+            PeriodicFlusher::start_with_flush_signal(m, Duration::from_secs(3600), libc::SIGUSR1)
+                .unwrap();
+        // SAFETY: the handler for SIGUSR1 was installed above.
+        unsafe { libc::raise(libc::SIGUSR1) };
+        std::thread::sleep(SIGNAL_POLL_INTERVAL * 4);
+        assert_eq!(dest.flushes().len(), 1);
+
+        drop(flusher);
+        assert_eq!(dest.flushes().len(), 2);
+    }
+}
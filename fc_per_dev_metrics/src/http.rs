@@ -0,0 +1,275 @@
+use crate::metrics::Metrics;
+use crate::prometheus;
+use crate::snapshot::SnapshotMetrics;
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Largest request head accepted, there is no use for headers here.
+const MAX_REQUEST_HEAD: usize = 8192;
+
+/// Time a client has to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Pause after an `accept` which found no client or failed, and so the delay
+/// for the server to notice it is being dropped.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+enum Listener {
+    Unix(UnixListener, PathBuf),
+    Tcp(TcpListener),
+}
+
+/// Minimal HTTP/1.1 server answering `GET /metrics` with the cumulative values
+/// of the metrics in the Prometheus text format.
+/// The metrics are read through `SnapshotMetrics::peek`, so scraping does not
+/// affect the deltas written by `Metrics::write`. Requests are served one at a
+/// time, each on its own connection, from a background thread which is stopped
+/// when the server is dropped.
+#[derive(Debug)]
+pub struct MetricsServer {
+    stop: Arc<AtomicBool>,
+    local_addr: Option<SocketAddr>,
+    socket_path: Option<PathBuf>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Serves the metrics on the Unix domain socket created at `path`.
+    pub fn bind_unix<T, M>(metrics: &'static Metrics<T, M>, path: impl AsRef<Path>) -> std::io::Result<Self>
+    where
+        T: SnapshotMetrics + Debug + Sync,
+        M: Write + Send + Debug,
+    {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path)?;
+        Self::spawn(metrics, Listener::Unix(listener, path))
+    }
+
+    /// Serves the metrics on `127.0.0.1:<port>`, or on a free port if `port`
+    /// is 0, see `local_addr`.
+    pub fn bind_localhost<T, M>(metrics: &'static Metrics<T, M>, port: u16) -> std::io::Result<Self>
+    where
+        T: SnapshotMetrics + Debug + Sync,
+        M: Write + Send + Debug,
+    {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        Self::spawn(metrics, Listener::Tcp(listener))
+    }
+
+    fn spawn<T, M>(metrics: &'static Metrics<T, M>, listener: Listener) -> std::io::Result<Self>
+    where
+        T: SnapshotMetrics + Debug + Sync,
+        M: Write + Send + Debug,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (local_addr, socket_path) = match &listener {
+            Listener::Unix(_, path) => (None, Some(path.clone())),
+            Listener::Tcp(listener) => (Some(listener.local_addr()?), None),
+        };
+        // The listener does not block, so that the thread keeps checking
+        // whether it has to stop.
+        match &listener {
+            Listener::Unix(listener, _) => listener.set_nonblocking(true)?,
+            Listener::Tcp(listener) => listener.set_nonblocking(true)?,
+        }
+        let thread_stop = Arc::clone(&stop);
+        let thread = std::thread::Builder::new()
+            .name("metrics-http".to_string())
+            .spawn(move || {
+                while !thread_stop.load(Ordering::Acquire) {
+                    let accepted = match &listener {
+                        Listener::Unix(listener, _) => listener.accept().map(|(mut stream, _)| {
+                            // Failing connections only affect the client which
+                            // made them.
+                            let _ = stream
+                                .set_nonblocking(false)
+                                .and_then(|_| stream.set_read_timeout(Some(READ_TIMEOUT)))
+                                .and_then(|_| serve(&mut stream, metrics));
+                        }),
+                        Listener::Tcp(listener) => listener.accept().map(|(mut stream, _)| {
+                            let _ = stream
+                                .set_nonblocking(false)
+                                .and_then(|_| stream.set_read_timeout(Some(READ_TIMEOUT)))
+                                .and_then(|_| serve(&mut stream, metrics));
+                        }),
+                    };
+                    // Besides `WouldBlock`, errors such as running out of file
+                    // descriptors last, so retrying right away would only spin.
+                    if accepted.is_err() {
+                        std::thread::sleep(ACCEPT_BACKOFF);
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            stop,
+            local_addr,
+            socket_path,
+            thread: Some(thread),
+        })
+    }
+
+    /// Address the server listens on, for servers bound to localhost.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        // The listener is closed by now, so no client can reach it any more.
+        if let Some(path) = &self.socket_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Answers the single request sent on `stream`.
+fn serve<S, T, M>(stream: &mut S, metrics: &Metrics<T, M>) -> std::io::Result<()>
+where
+    S: Read + Write,
+    T: SnapshotMetrics + Debug,
+    M: Write + Send + Debug,
+{
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return respond(stream, "431 Request Header Fields Too Large", &[], "");
+        }
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let (method, target) = (request_line.next(), request_line.next());
+    match (method, target) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = prometheus::render(&metrics.peek());
+            respond(
+                stream,
+                "200 OK",
+                &[("Content-Type", prometheus::CONTENT_TYPE)],
+                &body,
+            )
+        }
+        (Some("GET"), Some(_)) => respond(stream, "404 Not Found", &[], ""),
+        (Some(_), Some(_)) => respond(stream, "405 Method Not Allowed", &[("Allow", "GET")], ""),
+        _ => respond(stream, "400 Bad Request", &[], ""),
+    }
+}
+
+fn respond<S: Write>(
+    stream: &mut S,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> std::io::Result<()> {
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{leaked_metrics, IncMetric};
+    use std::net::TcpStream;
+    use std::os::unix::net::UnixStream;
+
+    fn get<S: Read + Write>(mut stream: S, target: &str) -> String {
+        write!(stream, "GET {target} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_scrape_over_tcp() {
+        let m = leaked_metrics::<Vec<u8>>();
+        m.init(Vec::new()).unwrap();
+        let eth0 = m.net.register("eth0").unwrap();
+        eth0.rx_count.add(3);
+
+        let server = MetricsServer::bind_localhost(m, 0).unwrap();
+        let addr = server.local_addr().unwrap();
+        let response = get(TcpStream::connect(addr).unwrap(), "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+        assert!(response.contains("firecracker_net_rx_count_total{device=\"eth0\"} 3\n"));
+
+        // Scraping does not consume the deltas of the file flush.
+        assert!(m.write().unwrap());
+        let written = m.reopen(Vec::new()).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(std::str::from_utf8(&written).unwrap().lines().next().unwrap())
+                .unwrap();
+        assert_eq!(json["net_eth0"]["rx_count"], 3);
+
+        // Counters stay cumulative across flushes.
+        eth0.rx_count.add(1);
+        let response = get(TcpStream::connect(addr).unwrap(), "/metrics");
+        assert!(response.contains("firecracker_net_rx_count_total{device=\"eth0\"} 4\n"));
+
+        let response = get(TcpStream::connect(addr).unwrap(), "/");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{response}");
+    }
+
+    #[test]
+    fn test_scrape_over_unix_socket() {
+        let m = leaked_metrics::<Vec<u8>>();
+        m.net.register("eth0").unwrap().tx_count.add(2);
+        let path = std::env::temp_dir().join(format!("fc_metrics_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let server = MetricsServer::bind_unix(m, &path).unwrap();
+        let response = get(UnixStream::connect(&path).unwrap(), "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("firecracker_net_tx_count_total{device=\"eth0\"} 2\n"));
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        write!(stream, "POST /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_server_stops_without_a_client() {
+        let m = leaked_metrics::<Vec<u8>>();
+        let mut server = MetricsServer::bind_localhost(m, 0).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        // No connection is needed to get the thread out of `accept`.
+        server.stop.store(true, Ordering::Release);
+        server.thread.take().unwrap().join().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
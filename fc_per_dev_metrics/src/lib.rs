@@ -0,0 +1,14 @@
+pub mod blockdevice;
+pub mod emf;
+pub mod exporter;
+pub mod flusher;
+pub mod http;
+pub mod metrics;
+pub mod netdevice;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod otlp;
+pub mod prometheus;
+pub mod rotation;
+pub mod signal;
+pub mod snapshot;
@@ -1,16 +1,18 @@
-mod metrics;
-mod netdevice;
-use crate::metrics::{METRICS, Metrics, FirecrackerMetrics};
+use fc_per_dev_metrics::emf::{EmfConfig, EmfExporter};
+use fc_per_dev_metrics::exporter::{JsonFormat, JsonOptions};
+use fc_per_dev_metrics::metrics::{METRICS, Metrics, FirecrackerMetrics};
 use std::time::SystemTime;
 use std::io::LineWriter;
 use std::fs::File;
-use crate::netdevice::Net;
-use crate::metrics::IncMetric;
+use fc_per_dev_metrics::netdevice::Net;
+use fc_per_dev_metrics::signal::install_fatal_signal_handlers;
+use std::os::unix::io::AsRawFd;
+use fc_per_dev_metrics::metrics::IncMetric;
 
 fn test_net_metrics(m: &Metrics<FirecrackerMetrics, LineWriter<File>>){
 // /*
-    let net0 = Net::new(String::from("net0"));
-    let net1 = Net::new(String::from("net1"));
+    let net0 = Net::new(String::from("eth0")).expect("Failed to add eth0");
+    let net1 = Net::new(String::from("eth1")).expect("Failed to add eth1");
     let t0 = SystemTime::now();
     net0.metrics.cfg_fails.add(10);
     net0.metrics.mac_address_updates.add(10);
@@ -78,7 +80,22 @@ fn main(){
     let m = &METRICS;
 
     let f = File::create("./metrics.json").expect("Failed to create temporary metrics file");
-    assert!(m.init(LineWriter::new(f)).is_ok());
+    install_fatal_signal_handlers(f.as_raw_fd()).expect("Failed to install signal handlers");
+    let config = EmfConfig::new()
+        .cloudwatch_namespace("TestNs")
+        .with_dimensions(["Sandbox"])
+        .set_property("Sandbox", "1234");
+    let emf = EmfExporter::new(std::io::stdout(), config).expect("Invalid EMF configuration");
+    // `metrics.json` holds one record per line, or indented records with `--pretty`.
+    let options = JsonOptions {
+        format: if std::env::args().any(|arg| arg == "--pretty") {
+            JsonFormat::Pretty
+        } else {
+            JsonFormat::Compact
+        },
+        ..Default::default()
+    };
+    assert!(m.init_with_options(LineWriter::new(f), options, vec![Box::new(emf)]).is_ok());
 
     test_net_metrics(m);
 }
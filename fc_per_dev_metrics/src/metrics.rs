@@ -2,13 +2,14 @@
 use std::fmt::Debug;
 use std::io::Write;
 use std::ops::Deref;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Mutex, OnceLock};
-use std::vec;
-use crate::netdevice::NetDeviceMetricsHelper;
-use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError, RwLock};
+use crate::blockdevice::BlockDeviceMetrics;
+use crate::exporter::{FcJsonExporter, JsonOptions, MetricsExporter};
+use crate::netdevice::NetDeviceMetrics;
+use crate::snapshot::{GroupScope, GroupSnapshot, MetricValue, MetricsSnapshot, SnapshotMetrics};
 
-use serde::{Serialize, Serializer, Deserialize, ser::SerializeMap};
+use serde::{Serialize, Serializer, ser::SerializeMap};
 
 pub type FcLineWriter = std::io::LineWriter<std::fs::File>;
 
@@ -20,17 +21,22 @@ pub static METRICS: Metrics<FirecrackerMetrics, FcLineWriter> =
 // All member fields have types which are Sync, and exhibit interior mutability, so
 // we can call operations on metrics using a non-mut static global variable.
 #[derive(Debug)]
-pub struct Metrics<T: Serialize, M: Write + Send> {
+pub struct Metrics<T: SnapshotMetrics, M: Write + Send> {
     // Metrics will get flushed here.
-    metrics_buf: OnceLock<Mutex<M>>,
+    metrics_buf: OnceLock<Mutex<MetricsSinks<M>>>,
     pub app_metrics: T,
 }
-#[derive(Debug, Deserialize)]
-pub struct EMFMetrics{
-    inner: BTreeMap<String,usize>,
+
+/// Destinations every flush fans out to.
+#[derive(Debug)]
+struct MetricsSinks<M> {
+    /// Firecracker JSON metrics written to the destination given at init.
+    dest: FcJsonExporter<M>,
+    /// Additional sinks registered at init.
+    exporters: Vec<Box<dyn MetricsExporter>>,
 }
 
-impl<T: Serialize + Debug, M: Write + Send + Debug> Metrics<T, M> {
+impl<T: SnapshotMetrics + Debug, M: Write + Send + Debug> Metrics<T, M> {
     /// Creates a new instance of the current metrics.
     // TODO: We need a better name than app_metrics (something that says that these are the actual
     // values that we are writing to the metrics_buf).
@@ -54,117 +60,78 @@ impl<T: Serialize + Debug, M: Write + Send + Debug> Metrics<T, M> {
     ///
     /// * `metrics_dest` - Buffer for JSON formatted metrics. Needs to implement `Write` and `Send`.
     pub fn init(&self, metrics_dest: M) -> Result<(), MetricsError> {
+        self.init_with_exporters(metrics_dest, Vec::new())
+    }
+
+    /// Same as `init`, but the metrics are also exported to every sink in
+    /// `exporters` on each flush.
+    ///
+    /// # Arguments
+    ///
+    /// * `metrics_dest` - Buffer for JSON formatted metrics. Needs to implement `Write` and `Send`.
+    /// * `exporters` - Additional sinks, in the order they get written to.
+    pub fn init_with_exporters(
+        &self,
+        metrics_dest: M,
+        exporters: Vec<Box<dyn MetricsExporter>>,
+    ) -> Result<(), MetricsError> {
+        self.init_with_options(metrics_dest, JsonOptions::default(), exporters)
+    }
+
+    /// Same as `init_with_exporters`, but the JSON metrics are written to
+    /// `metrics_dest` according to `options` instead of as one compact record
+    /// of deltas per line.
+    ///
+    /// # Arguments
+    ///
+    /// * `metrics_dest` - Buffer for JSON formatted metrics. Needs to implement `Write` and `Send`.
+    /// * `options` - Layout and temporality of the records written to `metrics_dest`.
+    /// * `exporters` - Additional sinks, in the order they get written to.
+    pub fn init_with_options(
+        &self,
+        metrics_dest: M,
+        options: JsonOptions,
+        exporters: Vec<Box<dyn MetricsExporter>>,
+    ) -> Result<(), MetricsError> {
         self.metrics_buf
-            .set(Mutex::new(metrics_dest))
+            .set(Mutex::new(MetricsSinks {
+                dest: FcJsonExporter::with_options(metrics_dest, options),
+                exporters,
+            }))
             .map_err(|_| MetricsError::AlreadyInitialized)
     }
 
-    pub fn print_emf(&self, fcmetrics: String) {
-        #[derive(Debug, Serialize,Deserialize)]
-        struct Emf{
-            utc_timestamp_ms: usize,
-            #[serde(flatten)]
-            innerm: BTreeMap<String,BTreeMap<String,usize>>,
-        }
-        #[derive(Debug, Serialize,Deserialize)]
-        struct Metric{
-            #[serde(rename = "Name")]
-            name: String,
-            #[serde(rename = "Unit")]
-            unit: String,
-        }
-        #[derive(Debug, Serialize,Deserialize)]
-        struct MetricDirective{
-            #[serde(rename = "Namespace")]
-            namespace: String,
-            #[serde(rename = "Dimensions")]
-            dimensions: Vec<Vec<String>>,
-            #[serde(rename = "Metrics")]
-            metrics: Vec<Metric>,
-            // Metrics: Vec<BTreeMap<String,String>>,
-        }
-        #[derive(Debug, Serialize,Deserialize)]
-        struct MetricDirectiveObj{
-            #[serde(rename = "Timestamp")]
-            timestamp: usize,
-            #[serde(rename = "CloudWatchMetrics")]
-            cloud_watch_metrics: Vec<MetricDirective>,
-        }
-
-        mod as_emf_metrics{
-            use super::*;
-            pub fn serialize<S>(metrics: &Vec<EMFMetrics>, serializer: S) -> Result<S::Ok, S::Error>
-            where
-                S: Serializer,
-                {
-                    let mut seq = serializer.serialize_map(Some(metrics.len()))?;
-                    for metric in metrics.iter() {
-                        for (key,value) in (*metric).inner.iter(){
-                            seq.serialize_entry(key,value)?;
-                        }
-                    }
-                    seq.end()
-                }
-        }
-        #[derive(Debug, Serialize)]
-        struct EmfStruct {
-            #[serde(flatten)]
-            aws: BTreeMap<String,MetricDirectiveObj>,
-            #[serde(rename = "SandboxId")]
-            sandbox_id: usize,
-            #[serde(flatten, with = "as_emf_metrics")]
-            metrics: Vec<EMFMetrics>,
-        }
-        let mut final_emf = EmfStruct{
-            aws: BTreeMap::new(),
-            sandbox_id: 1234,
-            metrics: Vec::new(),
-        };
-        final_emf.aws.insert("_aws".to_string(),
-                MetricDirectiveObj{
-                timestamp: 0,
-                cloud_watch_metrics: vec![MetricDirective{
-                namespace: "TestNs".to_string(),
-                dimensions: Vec::new(),
-                metrics: Vec::new(),
-                }]
-            }
-        );
-        fn get_unit(key: &str) -> String{
-            let mut unit = "Count".to_string();
-            if key.to_lowercase().ends_with("_bytes") || key.to_lowercase().ends_with("_bytes_count"){
-                unit = "Bytes".to_string();
-            }else if key.to_lowercase().ends_with("_ms"){
-                unit = "Milliseconds".to_string();
-            }else if key.to_lowercase().ends_with("_us") {
-                unit = "Microseconds".to_string()
-            }
-            unit
-        }
-        let emf = serde_json::from_str::<Emf>(fcmetrics.as_str()).unwrap();
-        // Timestamp = emf.utc_timestamp_ms;
-        let mobj = final_emf.aws.get_mut("_aws").unwrap();
-        mobj.timestamp = emf.utc_timestamp_ms;
-        mobj.cloud_watch_metrics[0].dimensions.push(vec!["Sandbox".to_string()]);
-        for (key,value) in emf.innerm.iter(){
-            for (k,v) in value.iter(){
-                let emfmetrics = EMFMetrics{
-                    inner: BTreeMap::from([(format!("{}.{}", key, k),*v)])
-                };
-                final_emf.metrics.push(emfmetrics);
-                mobj.cloud_watch_metrics[0].metrics.push(
-                    Metric{
-                        name: format!("{}.{}", key, k),
-                        unit: get_unit(k),
-                    }
-                );
-            }
+    /// Replaces the destination given at initialization with `metrics_dest`, e.g. to reopen a
+    /// FIFO after a snapshot restore or a file moved away by a log rotator, and returns the
+    /// previous destination.
+    /// Pending metrics are flushed to the previous destination first. The destination is
+    /// switched even if that flush fails, since a broken destination is the usual reason for
+    /// reopening it; the failure is accounted for in `missed_metrics_count`.
+    /// The additional exporters are left untouched.
+    /// If that flush finds out a thread panicked while holding the destination,
+    /// `MetricsError::LockPoisoned` is returned and the destination is kept, see `write`;
+    /// calling `reopen` again switches it.
+    ///
+    /// # Arguments
+    ///
+    /// * `metrics_dest` - Buffer for JSON formatted metrics. Needs to implement `Write` and `Send`.
+    pub fn reopen(&self, metrics_dest: M) -> Result<M, MetricsError> {
+        let lock = self.metrics_buf.get().ok_or_else(|| {
+            MetricsError::NeverInitialized("Metrics system is not initialized".to_string())
+        })?;
+        if let Err(MetricsError::LockPoisoned) = self.write() {
+            return Err(MetricsError::LockPoisoned);
         }
-        println!("{}",serde_json::to_string_pretty(&final_emf).unwrap());
+        let (mut guard, _) = self.lock_sinks(lock);
+        // Anything the old writer still buffers belongs to the old destination.
+        let _ = guard.dest.dest.flush();
+        Ok(std::mem::replace(&mut guard.dest.dest, metrics_dest))
     }
+
     /// Writes metrics to the destination provided as argument upon initialization of the metrics.
     /// Upon failure, an error is returned if metrics system is initialized and metrics could not be
-    /// written.
+    /// written. `MetricsError::LockPoisoned` is returned once after a thread panicked while
+    /// holding the destination, which is then recovered: the metrics are still written.
     /// Upon success, the function will return `True` (if metrics system was initialized and metrics
     /// were successfully written to disk) or `False` (if metrics system was not yet initialized).
     ///
@@ -172,49 +139,93 @@ impl<T: Serialize + Debug, M: Write + Send + Debug> Metrics<T, M> {
     /// is not meant to be used in a multithreaded scenario. The reason
     /// `metrics_buf` is enclosed in a `Mutex` is that `lazy_static` enforces
     /// thread-safety on all its members.
-    /// The only exception is for signal handlers that result in process exit, which may be run on
-    /// any thread. To prevent the race condition present in the serialisation step of
-    /// SharedIncMetrics, deadly signals use SharedStoreMetrics instead (which have a thread-safe
-    /// serialise implementation).
-    /// The only known caveat is that other metrics may not be properly written before exiting from
-    /// a signal handler. We make this compromise since the process will be killed anyway and the
-    /// important metric in this case is the signal one.
-    /// The alternative is to hold a Mutex over the entire function call, but this increases the
-    /// known deadlock potential.
+    /// Signal handlers that result in process exit must not call this function, since it locks
+    /// and allocates. They go through `signal::install_fatal_signal_handlers` instead, which
+    /// records the signal in `SignalMetrics::fatal_signal` (a SharedStoreMetric, which can be
+    /// stored without synchronization) and writes a minimal final JSON line straight to the
+    /// metrics file descriptor.
+    /// The only known caveat is that other metrics are not written before exiting from the signal
+    /// handler. We make this compromise since the process will be killed anyway and the important
+    /// metric in this case is the signal one.
     pub fn write(&self) -> Result<bool, MetricsError> {
         if let Some(lock) = self.metrics_buf.get() {
-            match serde_json::to_string_pretty(&self.app_metrics) {
-                Ok(msg) => {
-                    self.print_emf(msg.clone());
-                    if let Ok(mut guard) = lock.lock() {
-                        // No need to explicitly call flush because the underlying LineWriter
-                        // flushes automatically whenever a newline is
-                        // detected (and we always end with a newline the
-                        // current write).
-                        guard
-                            .write_all(format!("{msg}\n",).as_bytes())
-                            .map_err(MetricsError::Write)
-                            .map(|_| true)
-                    } else {
-                        // We have not incremented `missed_metrics_count` as there is no way to push
-                        // metrics if destination lock got poisoned.
-                        panic!(
-                            "Failed to write to the provided metrics destination due to poisoned \
-                             lock"
-                        );
+            let system = self.app_metrics.system_metrics();
+            let start_us = get_time_us(ClockType::Monotonic);
+            let (res, poisoned) = {
+                let (mut guard, poisoned) = self.lock_sinks(lock);
+                let snapshot = self.app_metrics.snapshot();
+                let sinks = &mut *guard;
+                // Every sink gets the snapshot even if an earlier one failed;
+                // the first error is reported.
+                let mut res = Ok(());
+                let exported = std::iter::once(sinks.dest.export(&snapshot))
+                    .chain(sinks.exporters.iter_mut().map(|e| e.export(&snapshot)));
+                for exported in exported {
+                    match exported {
+                        Ok(bytes) => {
+                            if let Some(system) = system {
+                                system.bytes_written.add(bytes);
+                            }
+                        }
+                        Err(err) => {
+                            if let Some(system) = system {
+                                system.record_error(&err);
+                            }
+                            res = res.and(Err(err));
+                        }
                     }
                 }
-                Err(err) => Err(MetricsError::Serde(err.to_string())),
+                (res, poisoned)
+            };
+            if let Some(system) = system {
+                if res.is_err() {
+                    system.missed_metrics_count.inc();
+                }
+                system
+                    .flush_duration_us
+                    .store(get_time_us(ClockType::Monotonic).saturating_sub(start_us) as usize);
             }
+            if poisoned {
+                return Err(MetricsError::LockPoisoned);
+            }
+            res.map(|_| true)
         } else {
             // If the metrics are not initialized, no error is thrown but we do let the user know
             // that metrics were not written.
             Ok(false)
         }
     }
+
+    /// Locks the destination and exporters, recovering them if a thread
+    /// panicked while holding the lock: they only hold writers, which at worst
+    /// got a partial record, so metrics keep flowing. The panic is accounted
+    /// for once in `metrics_fails`, and reported once through the returned
+    /// flag.
+    fn lock_sinks<'a>(
+        &self,
+        lock: &'a Mutex<MetricsSinks<M>>,
+    ) -> (MutexGuard<'a, MetricsSinks<M>>, bool) {
+        match lock.lock() {
+            Ok(guard) => (guard, false),
+            Err(poisoned) => {
+                if let Some(system) = self.app_metrics.system_metrics() {
+                    system.record_error(&MetricsError::LockPoisoned);
+                }
+                lock.clear_poison();
+                (poisoned.into_inner(), true)
+            }
+        }
+    }
+}
+
+/// Fresh metrics for tests, which need a `'static` instance without sharing
+/// `METRICS`.
+#[cfg(test)]
+pub(crate) fn leaked_metrics<M: Write + Send + Debug>() -> &'static Metrics<FirecrackerMetrics, M> {
+    Box::leak(Box::new(Metrics::new(FirecrackerMetrics::new())))
 }
 
-impl<T: Serialize + Debug, M: Write + Send + Debug> Deref for Metrics<T, M> {
+impl<T: SnapshotMetrics + Debug, M: Write + Send + Debug> Deref for Metrics<T, M> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -238,6 +249,18 @@ pub enum MetricsError {
     /// Writing the specified buffer failed.
     #[error("Failed to write metrics: {0}")]
     Write(std::io::Error),
+    /// Exporting the metrics in the Embedded Metric Format failed.
+    #[error("Failed to export EMF metrics: {0}")]
+    Emf(String),
+    /// Exporting the metrics over OpenTelemetry failed.
+    #[error("Failed to export OpenTelemetry metrics: {0}")]
+    Otel(String),
+    /// A device with the same id is already registered.
+    #[error("Device {0} is already registered")]
+    DuplicateDevice(String),
+    /// A thread panicked while holding the lock of the metrics destination.
+    #[error("Failed to write metrics due to poisoned lock")]
+    LockPoisoned,
 }
 
 /// Used for defining new types of metrics that act as a counter (i.e they are continuously updated
@@ -287,6 +310,12 @@ impl SharedIncMetric {
 /// from more than one thread, so more synchronization is necessary.
 #[derive(Debug, Default)]
 pub struct SharedStoreMetric(AtomicUsize);
+impl SharedStoreMetric {
+    /// Const default construction.
+    pub const fn new() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+}
 
 impl IncMetric for SharedIncMetric {
     // While the order specified for this operation is still Relaxed, the actual instruction will
@@ -302,8 +331,8 @@ impl IncMetric for SharedIncMetric {
     }
 
     fn fetch_diff(&self) -> usize {
-        let res = self.0.load(Ordering::Relaxed) - self.1.load(Ordering::Relaxed);
-        res
+        // The old value may be ahead of a concurrent `add`, see `Metric::flush`.
+        self.0.load(Ordering::Relaxed).saturating_sub(self.1.load(Ordering::Relaxed))
     }
 }
 
@@ -318,18 +347,12 @@ impl StoreMetric for SharedStoreMetric {
 }
 
 impl Serialize for SharedIncMetric {
-    /// Reset counters of each metrics. Here we suppose that Serialize's goal is to help with the
-    /// flushing of metrics.
-    /// !!! Any print of the metrics will also reset them. Use with caution !!!
+    /// Serializes the diff since the last flush without resetting it, so that
+    /// printing the metrics has no side effect. Counters only get reset by
+    /// `SnapshotMetrics::snapshot`.
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         // There's no serializer.serialize_usize() for some reason :(
-        let snapshot = self.0.load(Ordering::Relaxed);
-        let res = serializer.serialize_u64(snapshot as u64 - self.1.load(Ordering::Relaxed) as u64);
-
-        if res.is_ok() {
-            self.1.store(snapshot, Ordering::Relaxed);
-        }
-        res
+        serializer.serialize_u64(self.fetch_diff() as u64)
     }
 }
 
@@ -339,36 +362,525 @@ impl Serialize for SharedStoreMetric {
     }
 }
 
-///////////////////////////////////////////////////////////////////////////////
-///////////////////////////////////////////////////////////////////////////////
-/// Trait to be implemented by all devices having metrics that need to be tracked.
-pub trait PerDeviceMetricsHelper{
-    /// each device implements this function to serialize its metrics
-    fn serialize_metrics<S:Serializer>(serializer: S) -> Result<S::Ok, S::Error>;
+/// Kind of value a metric holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    /// Counter reported as the diff since the last flush (`SharedIncMetric`).
+    Counter,
+    /// Value reported as is (`SharedStoreMetric`).
+    Gauge,
+}
+
+/// Unit of a metric, as understood by the supported backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricUnit {
+    Count,
+    Bytes,
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    CountPerSecond,
+    BytesPerSecond,
 }
 
-#[derive(Default, Debug)]
-pub struct NetDeviceMetricsDummmy{}
-impl NetDeviceMetricsDummmy{
-    pub const fn new() -> Self{
-        Self{}
+impl MetricUnit {
+    /// Guesses the unit from the suffix of the metric name, for metrics which
+    /// do not declare one.
+    pub fn infer(name: &str) -> Self {
+        let name = name.to_lowercase();
+        if name.ends_with("_bytes") || name.ends_with("_bytes_count") {
+            MetricUnit::Bytes
+        } else if name.ends_with("_ms") {
+            MetricUnit::Milliseconds
+        } else if name.ends_with("_us") {
+            MetricUnit::Microseconds
+        } else {
+            MetricUnit::Count
+        }
+    }
+
+    /// Name of the unit in the CloudWatch Embedded Metric Format.
+    pub fn as_emf(&self) -> &'static str {
+        match self {
+            MetricUnit::Count => "Count",
+            MetricUnit::Bytes => "Bytes",
+            MetricUnit::Seconds => "Seconds",
+            MetricUnit::Milliseconds => "Milliseconds",
+            MetricUnit::Microseconds => "Microseconds",
+            MetricUnit::CountPerSecond => "Count/Second",
+            MetricUnit::BytesPerSecond => "Bytes/Second",
+        }
+    }
+
+    /// UCUM code of the unit, as used by OpenTelemetry.
+    pub fn as_ucum(&self) -> &'static str {
+        match self {
+            MetricUnit::Count => "1",
+            MetricUnit::Bytes => "By",
+            MetricUnit::Seconds => "s",
+            MetricUnit::Milliseconds => "ms",
+            MetricUnit::Microseconds => "us",
+            MetricUnit::CountPerSecond => "1/s",
+            MetricUnit::BytesPerSecond => "By/s",
+        }
     }
 }
 
-impl Serialize for NetDeviceMetricsDummmy{
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-        where
-            S: Serializer {
-                NetDeviceMetricsHelper::serialize_metrics(serializer)
+/// Metadata of a single field of a `MetricGroup`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricField {
+    /// Name of the field, which is also the serialized name of the metric.
+    pub name: &'static str,
+    /// Doc comment of the field.
+    pub description: &'static str,
+    pub kind: MetricKind,
+    /// Unit declared through `#[unit = ...]`, if any.
+    pub declared_unit: Option<MetricUnit>,
+}
+
+impl MetricField {
+    /// Declared unit of the metric, inferred from its name if not declared.
+    pub fn unit(&self) -> MetricUnit {
+        self.declared_unit
+            .unwrap_or_else(|| MetricUnit::infer(self.name))
     }
 }
 
-#[derive(Debug, Default)]
-struct SerializeToUtcTimestampMs;
-impl SerializeToUtcTimestampMs {
+/// Implemented by the metric types a `MetricGroup` can be made of.
+pub trait Metric: Serialize + Debug + Send + Sync {
+    const KIND: MetricKind;
+    /// Adds the value of `other` to `self`, the same way it is serialized.
+    fn aggregate(&self, other: &Self);
+    /// Returns the value to report for the current flush interval along with
+    /// the cumulative value of the metric, and resets the metric for the next
+    /// interval.
+    fn flush(&self) -> (u64, u64);
+    /// Same as `flush`, without resetting the metric.
+    fn peek(&self) -> (u64, u64);
+}
+
+impl Metric for SharedIncMetric {
+    const KIND: MetricKind = MetricKind::Counter;
+
+    fn aggregate(&self, other: &Self) {
+        self.add(other.fetch_diff());
+    }
+
+    fn flush(&self) -> (u64, u64) {
+        let current = self.0.load(Ordering::Relaxed);
+        // `fetch_max` makes concurrent flushes report every increment once:
+        // whoever observed the highest value accounts for the increments
+        // between the other snapshots.
+        let old = self.1.fetch_max(current, Ordering::Relaxed);
+        (current.saturating_sub(old) as u64, current.max(old) as u64)
+    }
+
+    fn peek(&self) -> (u64, u64) {
+        let current = self.0.load(Ordering::Relaxed);
+        (self.fetch_diff() as u64, current as u64)
+    }
+}
+
+impl Metric for SharedStoreMetric {
+    const KIND: MetricKind = MetricKind::Gauge;
+
+    fn aggregate(&self, other: &Self) {
+        self.store(self.fetch() + other.fetch());
+    }
+
+    fn flush(&self) -> (u64, u64) {
+        let value = self.fetch() as u64;
+        (value, value)
+    }
+
+    fn peek(&self) -> (u64, u64) {
+        self.flush()
+    }
+}
+
+/// Implemented by structs made only of metrics, usually through `metric_group!`.
+pub trait MetricGroup {
+    /// Metadata of every field, in declaration order.
+    const FIELDS: &'static [MetricField];
+    /// Field-wise aggregation of `other` into `self`.
+    fn aggregate(&mut self, other: &Self);
+    /// Flushes every field, in declaration order.
+    fn flush(&self) -> Vec<MetricValue>;
+    /// Reads every field without resetting it, in declaration order.
+    fn peek(&self) -> Vec<MetricValue>;
+}
+
+/// Pairs the `(value, total)` read from every field of a `MetricGroup` with
+/// the metadata of the field.
+#[doc(hidden)]
+pub fn metric_values<I>(fields: &'static [MetricField], values: I) -> Vec<MetricValue>
+where
+    I: IntoIterator<Item = (u64, u64)>,
+{
+    fields
+        .iter()
+        .zip(values)
+        .map(|(field, (value, total))| MetricValue {
+            field,
+            value,
+            total,
+        })
+        .collect()
+}
+
+/// Flushes `group`, or only reads it if `flush` is false.
+fn read_group<G: MetricGroup>(group: &G, flush: bool) -> Vec<MetricValue> {
+    if flush {
+        group.flush()
+    } else {
+        group.peek()
+    }
+}
+
+/// Declares a struct made of `SharedIncMetric`/`SharedStoreMetric` fields and
+/// generates its const `new()` along with its `MetricGroup` implementation, so
+/// that no field can be forgotten in either of them.
+/// Fields may only carry doc comments, which become their description,
+/// optionally followed by a `#[unit = <MetricUnit variant>]` attribute.
+///
+/// ```ignore
+/// metric_group! {
+///     #[derive(Debug, Default, Serialize)]
+///     pub struct FooMetrics {
+///         /// Number of foos.
+///         pub foo_count: SharedIncMetric,
+///         /// Size of the foos.
+///         #[unit = Bytes]
+///         pub foo_size: SharedStoreMetric,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! metric_group {
+    (@unit) => { None };
+    (@unit $unit:ident) => { Some($crate::metrics::MetricUnit::$unit) };
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[doc = $doc:literal])*
+                $(#[unit = $unit:ident])?
+                $field_vis:vis $field:ident: $ty:ty,
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $(
+                $(#[doc = $doc])*
+                $field_vis $field: $ty,
+            )*
+        }
+
+        impl $name {
+            /// Const default construction.
+            pub const fn new() -> Self {
+                Self {
+                    $($field: <$ty>::new(),)*
+                }
+            }
+        }
+
+        impl $crate::metrics::MetricGroup for $name {
+            const FIELDS: &'static [$crate::metrics::MetricField] = &[
+                $($crate::metrics::MetricField {
+                    name: stringify!($field),
+                    description: concat!($($doc),*),
+                    kind: <$ty as $crate::metrics::Metric>::KIND,
+                    declared_unit: $crate::metric_group!(@unit $($unit)?),
+                },)*
+            ];
+
+            fn aggregate(&mut self, other: &Self) {
+                $($crate::metrics::Metric::aggregate(&self.$field, &other.$field);)*
+            }
+
+            fn flush(&self) -> Vec<$crate::snapshot::MetricValue> {
+                let values = [$($crate::metrics::Metric::flush(&self.$field),)*];
+                $crate::metrics::metric_values(Self::FIELDS, values)
+            }
+
+            fn peek(&self) -> Vec<$crate::snapshot::MetricValue> {
+                let values = [$($crate::metrics::Metric::peek(&self.$field),)*];
+                $crate::metrics::metric_values(Self::FIELDS, values)
+            }
+        }
+    };
+}
+
+///////////////////////////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////////////////////////
+/// Trait to be implemented by the metrics of every device type that is tracked
+/// per device.
+pub trait DeviceMetrics: MetricGroup + Serialize + Debug + Default + Send + Sync + 'static {
+    /// Name of the aggregate entry, also used as prefix of the per-device
+    /// entries (e.g. `net`, `net_eth0`).
+    const PREFIX: &'static str;
+}
+
+/// Registry of the metrics of every device of one type.
+/// Devices can be created and hot-plugged from several threads, so the
+/// registry is protected by a `RwLock`. Each device's metrics live behind an
+/// `Arc`, which keeps the handle held by the device valid even when the `Vec`
+/// reallocates.
+/// The registry serializes to an aggregate entry named after
+/// `DeviceMetrics::PREFIX` followed by one entry per device.
+#[derive(Debug)]
+pub struct PerDeviceMetrics<D> {
+    metrics: RwLock<DeviceRegistry<D>>,
+}
+
+#[derive(Debug)]
+struct DeviceRegistry<D> {
+    entries: Vec<DeviceMetricsEntry<D>>,
+    /// Registration index of the next entry, which keys the devices without
+    /// id. It is never reused, so that such a device does not get the key of
+    /// one which is still being flushed.
+    next_index: usize,
+    /// Sum of the counter totals of the devices dropped from `entries`, in
+    /// field order, or empty if none was.
+    retired_totals: Vec<u64>,
+}
+
+/// Metrics of a single device along with the key they are serialized under.
+#[derive(Debug)]
+struct DeviceMetricsEntry<D> {
+    key: String,
+    /// Id of the device, or `key` if the device has no id.
+    device: String,
+    /// UTC time at which the device got registered, in nanoseconds.
+    start_time_ns: u64,
+    metrics: Arc<D>,
+    /// Set once the device is gone. The entry is dropped after the next
+    /// snapshot, so that the final deltas get out, whether or not the sinks
+    /// then accept them. Until `Metrics::init`, no snapshot gets taken and
+    /// removed entries are kept.
+    removed: AtomicBool,
+}
+
+impl<D: DeviceMetrics> PerDeviceMetrics<D> {
     /// Const default construction.
     pub const fn new() -> Self {
-        SerializeToUtcTimestampMs
+        Self {
+            metrics: RwLock::new(DeviceRegistry {
+                entries: Vec::new(),
+                next_index: 0,
+                retired_totals: Vec::new(),
+            }),
+        }
+    }
+
+    /// Allocates the metrics of a new device and registers them so that
+    /// they get serialized on every flush.
+    /// The metrics are serialized as `<prefix>_<id>`, or as
+    /// `<prefix><index>` if the device has no id. A device registered with
+    /// the id of a removed device which is not flushed yet (e.g. re-plugged)
+    /// takes its metrics over, while the id of a device which is still
+    /// present is rejected, so that two devices never report as one.
+    pub fn register(&self, id: &str) -> Result<Arc<D>, MetricsError> {
+        // The registry only holds atomics, so a panic while holding the lock
+        // cannot leave it in an inconsistent state.
+        let mut registry = self.metrics.write().unwrap_or_else(PoisonError::into_inner);
+        if !id.is_empty() {
+            let key = format!("{}_{}", D::PREFIX, id);
+            if let Some(entry) = registry.entries.iter().find(|e| e.key == key) {
+                if !entry.removed.swap(false, Ordering::AcqRel) {
+                    return Err(MetricsError::DuplicateDevice(key));
+                }
+                return Ok(Arc::clone(&entry.metrics));
+            }
+        }
+
+        let (key, device) = if id.is_empty() {
+            let key = format!("{}{}", D::PREFIX, registry.next_index);
+            (key.clone(), key)
+        } else {
+            (format!("{}_{}", D::PREFIX, id), id.to_string())
+        };
+        registry.next_index += 1;
+        let metrics = Arc::new(D::default());
+        registry.entries.push(DeviceMetricsEntry {
+            key,
+            device,
+            start_time_ns: get_time_ns(ClockType::Real),
+            metrics: Arc::clone(&metrics),
+            removed: AtomicBool::new(false),
+        });
+        Ok(metrics)
+    }
+
+    /// Marks the metrics of a removed device so that they get flushed one
+    /// last time and then dropped from the registry.
+    pub fn unregister(&self, metrics: &Arc<D>) {
+        let registry = self.metrics.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(entry) = registry.entries.iter().find(|e| Arc::ptr_eq(&e.metrics, metrics)) {
+            entry.removed.store(true, Ordering::Release);
+        }
+    }
+
+    /// Reads the aggregate followed by every device into `groups`. Unless
+    /// `flush` is set, the metrics are not reset and the removed devices are
+    /// not dropped.
+    pub fn collect_into(&self, groups: &mut Vec<GroupSnapshot>, flush: bool) {
+        // Devices being added while we flush wait for the read lock to be
+        // released instead of racing with the iteration below.
+        let metrics = self.metrics.read().unwrap_or_else(PoisonError::into_inner);
+
+        // Only the devices seen as removed before their final deltas are
+        // flushed can be dropped afterwards.
+        let removed: Vec<bool> = metrics
+            .entries
+            .iter()
+            .map(|dev| flush && dev.removed.load(Ordering::Acquire))
+            .collect();
+
+        let devices: Vec<GroupSnapshot> = metrics
+            .entries
+            .iter()
+            .map(|dev| GroupSnapshot {
+                name: dev.key.clone(),
+                family: D::PREFIX,
+                scope: GroupScope::Device(dev.device.clone()),
+                start_time_ns: dev.start_time_ns,
+                values: read_group(dev.metrics.as_ref(), flush),
+            })
+            .collect();
+
+        // Counter totals of the dropped devices carry on in the aggregate.
+        let flushed_removed: Vec<(Arc<D>, Vec<u64>)> = metrics
+            .entries
+            .iter()
+            .zip(devices.iter())
+            .zip(removed)
+            .filter(|(_, removed)| *removed)
+            .map(|((dev, snapshot), _)| (Arc::clone(&dev.metrics), counter_totals(&snapshot.values)))
+            .collect();
+        let retired_totals = metrics.retired_totals.clone();
+        drop(metrics);
+
+        // The aggregate is built from the very deltas emitted per device, so
+        // increments landing during the flush can not make them diverge.
+        let mut aggregated: Vec<MetricValue> = D::FIELDS
+            .iter()
+            .map(|field| MetricValue {
+                field,
+                value: 0,
+                total: 0,
+            })
+            .collect();
+        for dev in devices.iter() {
+            for (agg, metric) in aggregated.iter_mut().zip(dev.values.iter()) {
+                agg.value += metric.value;
+                agg.total += metric.total;
+            }
+        }
+        for (agg, retired) in aggregated.iter_mut().zip(retired_totals.iter()) {
+            agg.total += retired;
+        }
+
+        groups.push(GroupSnapshot {
+            name: D::PREFIX.to_string(),
+            family: D::PREFIX,
+            scope: GroupScope::Aggregate,
+            // Counters of removed devices stay in the totals, which are then
+            // cumulative since the start of the process.
+            start_time_ns: process_start_time_ns(),
+            values: aggregated,
+        });
+        groups.extend(devices);
+
+        if !flushed_removed.is_empty() {
+            let mut registry = self.metrics.write().unwrap_or_else(PoisonError::into_inner);
+            let DeviceRegistry {
+                entries,
+                retired_totals,
+                ..
+            } = &mut *registry;
+            entries.retain(|dev| {
+                let Some((_, totals)) = flushed_removed.iter().find(|(m, _)| Arc::ptr_eq(m, &dev.metrics))
+                else {
+                    return true;
+                };
+                // Devices re-plugged in the meantime revived their entry,
+                // which is then kept.
+                if !dev.removed.load(Ordering::Acquire) {
+                    return true;
+                }
+                retire(retired_totals, totals);
+                false
+            });
+        }
+    }
+}
+
+/// Totals of the counters in `values`, gauges being left out as 0.
+fn counter_totals(values: &[MetricValue]) -> Vec<u64> {
+    values
+        .iter()
+        .map(|value| match value.field.kind {
+            MetricKind::Counter => value.total,
+            MetricKind::Gauge => 0,
+        })
+        .collect()
+}
+
+/// Adds the counter totals of a dropped device to `retired_totals`.
+fn retire(retired_totals: &mut Vec<u64>, totals: &[u64]) {
+    retired_totals.resize(totals.len(), 0);
+    for (retired, total) in retired_totals.iter_mut().zip(totals) {
+        *retired += total;
+    }
+}
+
+impl<D: DeviceMetrics> Default for PerDeviceMetrics<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: DeviceMetrics> Serialize for PerDeviceMetrics<D> {
+    /// Serializes the aggregate and devices without flushing them, the way
+    /// they are written by `Metrics::write`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut groups = Vec::new();
+        self.collect_into(&mut groups, false);
+        let mut map = serializer.serialize_map(Some(groups.len()))?;
+        for group in groups.iter() {
+            map.serialize_entry(&group.name, group)?;
+        }
+        map.end()
+    }
+}
+
+/// Metrics of a device, registered in a `PerDeviceMetrics` for as long as
+/// the handle lives.
+#[derive(Debug)]
+pub struct DeviceHandle<D: DeviceMetrics> {
+    #[allow(dead_code)]
+    pub(crate) id: String,
+    pub metrics: Arc<D>,
+    registry: &'static PerDeviceMetrics<D>,
+}
+
+impl<D: DeviceMetrics> DeviceHandle<D> {
+    /// Registers the metrics of device `id`, see `PerDeviceMetrics::register`.
+    pub fn register(registry: &'static PerDeviceMetrics<D>, id: String) -> Result<Self, MetricsError> {
+        Ok(Self {
+            metrics: registry.register(&id)?,
+            id,
+            registry,
+        })
+    }
+}
+
+impl<D: DeviceMetrics> Drop for DeviceHandle<D> {
+    fn drop(&mut self) {
+        self.registry.unregister(&self.metrics);
     }
 }
 
@@ -397,6 +909,9 @@ impl From<ClockType> for libc::clockid_t {
         }
     }
 }
+pub fn get_time_us(clock_type: ClockType) -> u64 {
+    get_time_ns(clock_type) / 1000
+}
 pub fn get_time_ns(clock_type: ClockType) -> u64 {
     let mut time_struct = libc::timespec {
         tv_sec: 0,
@@ -409,33 +924,106 @@ pub fn get_time_ns(clock_type: ClockType) -> u64 {
         + u64::try_from(time_struct.tv_nsec).unwrap()
 }
 
-impl Serialize for SerializeToUtcTimestampMs {
-    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.serialize_i64(
-            i64::try_from(get_time_ns(ClockType::Real) / 1_000_000)
-                .unwrap(),
-        )
+/// UTC time at which the process started, in nanoseconds, which is when the
+/// counters of the static metrics start counting.
+/// Falls back to the time of the first call if `/proc` is not available.
+pub fn process_start_time_ns() -> u64 {
+    static START: OnceLock<u64> = OnceLock::new();
+    *START.get_or_init(|| {
+        let now_ns = get_time_ns(ClockType::Real);
+        // The start time in `/proc/self/stat` is in clock ticks since boot.
+        let since_boot_ticks = std::fs::read_to_string("/proc/self/stat")
+            .ok()
+            .and_then(|stat| {
+                // Fields are counted from the one after the command name,
+                // which is the 3rd one and may contain spaces.
+                let fields = &stat[stat.rfind(')')? + 1..];
+                fields.split_whitespace().nth(19)?.parse::<u64>().ok()
+            });
+        // SAFETY: Safe because `_SC_CLK_TCK` is a valid name.
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        let mut boot_time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // SAFETY: Safe because the parameters are valid.
+        let uptime_ns = match unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut boot_time) } {
+            0 => u64::try_from(boot_time.tv_sec).ok().and_then(|secs| {
+                secs.checked_mul(NANOS_PER_SECOND)?
+                    .checked_add(u64::try_from(boot_time.tv_nsec).ok()?)
+            }),
+            _ => None,
+        };
+        match (since_boot_ticks, u64::try_from(ticks_per_second), uptime_ns) {
+            (Some(ticks), Ok(per_second), Some(uptime_ns)) if per_second > 0 => {
+                let since_boot_ns = ticks.saturating_mul(NANOS_PER_SECOND) / per_second;
+                now_ns.saturating_sub(uptime_ns.saturating_sub(since_boot_ns))
+            }
+            _ => now_ns,
+        }
+    })
+}
+
+metric_group! {
+    /// Metrics about the metrics system itself, so that a degrading metrics
+    /// pipeline can be alerted on.
+    #[derive(Debug, Default, Serialize)]
+    pub struct MetricsSystemMetrics {
+        /// Number of flushes which could not be exported to every sink.
+        pub missed_metrics_count: SharedIncMetric,
+        /// Number of failed writes to a metrics sink.
+        pub metrics_fails: SharedIncMetric,
+        /// Number of metrics snapshots which could not be serialized.
+        pub serialization_fails: SharedIncMetric,
+        /// Duration of the previous flush.
+        #[unit = Microseconds]
+        pub flush_duration_us: SharedStoreMetric,
+        /// Number of bytes written to the metrics sinks.
+        #[unit = Bytes]
+        pub bytes_written: SharedIncMetric,
+    }
+}
+
+impl MetricsSystemMetrics {
+    fn record_error(&self, err: &MetricsError) {
+        match err {
+            MetricsError::Serde(_) => self.serialization_fails.inc(),
+            _ => self.metrics_fails.inc(),
+        }
     }
 }
+
+metric_group! {
+    /// Metrics related to signals.
+    // Deadly signals are recorded from the signal handler, so these use
+    // `SharedStoreMetric`s which can be written without synchronization.
+    #[derive(Debug, Default, Serialize)]
+    pub struct SignalMetrics {
+        /// Number of the fatal signal which killed the process, if any.
+        pub fatal_signal: SharedStoreMetric,
+    }
+}
+
 /// Structure storing all metrics while enforcing serialization support on them.
-#[derive(Serialize)]
+/// Each device type gets a `PerDeviceMetrics` field, read in `collect`.
+#[derive(Debug)]
 pub struct FirecrackerMetrics {
-    utc_timestamp_ms: SerializeToUtcTimestampMs,
-    #[serde(flatten)]
-    pub net: NetDeviceMetricsDummmy,
+    pub metrics: MetricsSystemMetrics,
+    pub signals: SignalMetrics,
+    pub net: PerDeviceMetrics<NetDeviceMetrics>,
+    pub block: PerDeviceMetrics<BlockDeviceMetrics>,
 }
 
-impl Default for FirecrackerMetrics {
-    fn default() -> Self {
-        Self::new()
+impl Serialize for FirecrackerMetrics {
+    /// Serializes `SnapshotMetrics::peek`, which leaves the deltas in place.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.peek().serialize(serializer)
     }
 }
 
-impl Debug for FirecrackerMetrics {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("FirecrackerMetrics")
-            .field("net", &self.net)
-            .finish()
+impl Default for FirecrackerMetrics {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -445,8 +1033,514 @@ impl FirecrackerMetrics {
     /// Const default construction.
     pub const fn new() -> Self {
         Self {
-            utc_timestamp_ms: SerializeToUtcTimestampMs::new(),
-            net: NetDeviceMetricsDummmy::new(),
+            metrics: MetricsSystemMetrics::new(),
+            signals: SignalMetrics::new(),
+            net: PerDeviceMetrics::new(),
+            block: PerDeviceMetrics::new(),
+        }
+    }
+}
+
+impl FirecrackerMetrics {
+    fn collect(&self, flush: bool) -> MetricsSnapshot {
+        let mut groups = vec![
+            GroupSnapshot {
+                name: "metrics".to_string(),
+                family: "metrics",
+                scope: GroupScope::Single,
+                start_time_ns: process_start_time_ns(),
+                values: read_group(&self.metrics, flush),
+            },
+            GroupSnapshot {
+                name: "signals".to_string(),
+                family: "signals",
+                scope: GroupScope::Single,
+                start_time_ns: process_start_time_ns(),
+                values: read_group(&self.signals, flush),
+            },
+        ];
+        self.net.collect_into(&mut groups, flush);
+        self.block.collect_into(&mut groups, flush);
+        MetricsSnapshot {
+            utc_timestamp_ms: get_time_ns(ClockType::Real) / 1_000_000,
+            groups,
+        }
+    }
+}
+
+impl SnapshotMetrics for FirecrackerMetrics {
+    fn snapshot(&self) -> MetricsSnapshot {
+        self.collect(true)
+    }
+
+    fn peek(&self) -> MetricsSnapshot {
+        self.collect(false)
+    }
+
+    fn system_metrics(&self) -> Option<&MetricsSystemMetrics> {
+        Some(&self.metrics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter::JsonFormat;
+    use crate::snapshot::Temporality;
+
+    #[test]
+    fn test_metric_group_fields() {
+        let json = serde_json::to_value(NetDeviceMetrics::new()).unwrap();
+        let names: Vec<&str> = json.as_object().unwrap().keys().map(String::as_str).collect();
+        let mut fields: Vec<&str> = NetDeviceMetrics::FIELDS.iter().map(|f| f.name).collect();
+        fields.sort();
+        assert_eq!(names, fields);
+        assert!(NetDeviceMetrics::FIELDS
+            .iter()
+            .all(|f| f.kind == MetricKind::Counter && !f.description.is_empty()));
+    }
+
+    #[test]
+    fn test_metric_units() {
+        let unit = |name: &str| {
+            NetDeviceMetrics::FIELDS
+                .iter()
+                .find(|f| f.name == name)
+                .unwrap()
+                .unit()
+        };
+        assert_eq!(unit("rx_bytes_count"), MetricUnit::Bytes);
+        assert_eq!(unit("rx_rate_limiter_throttled"), MetricUnit::Count);
+
+        assert_eq!(MetricUnit::infer("flush_duration_us"), MetricUnit::Microseconds);
+        assert_eq!(MetricUnit::infer("read_bytes"), MetricUnit::Bytes);
+        assert_eq!(MetricUnit::infer("rx_count"), MetricUnit::Count);
+    }
+
+    #[test]
+    fn test_per_device_metrics_key() {
+        let registry = PerDeviceMetrics::<NetDeviceMetrics>::new();
+        registry.register("eth0").unwrap().tx_count.add(5);
+        registry.register("").unwrap();
+
+        let json = serde_json::to_value(&registry).unwrap();
+        assert_eq!(json["net"]["tx_count"], 5);
+        assert_eq!(json["net_eth0"]["tx_count"], 5);
+        assert_eq!(json["net1"]["tx_count"], 0);
+    }
+
+    #[test]
+    fn test_removed_device_flushed_once() {
+        let registry = PerDeviceMetrics::<BlockDeviceMetrics>::new();
+        let vda = registry.register("vda").unwrap();
+        registry.register("vdb").unwrap().read_count.add(1);
+        vda.read_count.add(3);
+        registry.unregister(&vda);
+
+        let json = serde_json::to_value(snapshot(&registry)).unwrap();
+        assert_eq!(json["block"]["read_count"], 4);
+        assert_eq!(json["block_vda"]["read_count"], 3);
+
+        let json = serde_json::to_value(snapshot(&registry)).unwrap();
+        assert_eq!(json["block"]["read_count"], 0);
+        assert!(json.get("block_vda").is_none());
+        assert!(json.get("block_vdb").is_some());
+    }
+
+    #[test]
+    fn test_device_removed_before_any_flush() {
+        let registry = PerDeviceMetrics::<NetDeviceMetrics>::new();
+        let eth0 = registry.register("eth0").unwrap();
+        eth0.rx_count.add(3);
+        registry.unregister(&eth0);
+        drop(eth0);
+
+        // The entry waits for the first snapshot, however late it comes.
+        let mut groups = Vec::new();
+        registry.collect_into(&mut groups, false);
+        assert_eq!(groups.len(), 2);
+        let json = serde_json::to_value(snapshot(&registry)).unwrap();
+        assert_eq!(json["net_eth0"]["rx_count"], 3);
+        assert_eq!(json["net"]["rx_count"], 3);
+        assert!(serde_json::to_value(snapshot(&registry)).unwrap().get("net_eth0").is_none());
+    }
+
+    #[test]
+    fn test_device_keys_are_unique() {
+        let registry = PerDeviceMetrics::<NetDeviceMetrics>::new();
+        let first = registry.register("").unwrap();
+        registry.register("").unwrap();
+        registry.unregister(&first);
+        let _ = snapshot(&registry);
+        registry.register("").unwrap();
+
+        let snap = snapshot(&registry);
+        let keys: Vec<&str> = snap.groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(keys, ["net", "net1", "net2"]);
+    }
+
+    #[test]
+    fn test_replugged_device_shares_its_entry() {
+        let registry = PerDeviceMetrics::<NetDeviceMetrics>::new();
+        let eth0 = registry.register("eth0").unwrap();
+        eth0.rx_count.add(2);
+        registry.unregister(&eth0);
+        let replugged = registry.register("eth0").unwrap();
+        replugged.rx_count.add(3);
+
+        let snap = snapshot(&registry);
+        let keys: Vec<&str> = snap.groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(keys, ["net", "net_eth0"]);
+        assert_eq!(serde_json::to_value(&snap).unwrap()["net_eth0"]["rx_count"], 5);
+
+        // The entry outlived the flush and goes with the re-plugged device.
+        registry.unregister(&replugged);
+        let _ = snapshot(&registry);
+        assert_eq!(snapshot(&registry).groups.len(), 1);
+    }
+
+    #[test]
+    fn test_duplicate_device_is_rejected() {
+        let registry = PerDeviceMetrics::<NetDeviceMetrics>::new();
+        let eth0 = registry.register("eth0").unwrap();
+        assert!(matches!(
+            registry.register("eth0"),
+            Err(MetricsError::DuplicateDevice(key)) if key == "net_eth0"
+        ));
+        eth0.rx_count.add(1);
+
+        // The rejected device took nothing over, nor did it go away with it.
+        let snap = snapshot(&registry);
+        assert_eq!(snap.groups.len(), 2);
+        assert_eq!(serde_json::to_value(&snap).unwrap()["net_eth0"]["rx_count"], 1);
+        assert_eq!(snapshot(&registry).groups.len(), 2);
+    }
+
+    #[test]
+    fn test_aggregate_totals_survive_removal() {
+        let registry = PerDeviceMetrics::<NetDeviceMetrics>::new();
+        let eth0 = registry.register("eth0").unwrap();
+        let eth1 = registry.register("eth1").unwrap();
+        eth0.rx_count.add(10);
+        eth1.rx_count.add(10);
+        registry.unregister(&eth1);
+
+        let total = |groups: &[GroupSnapshot]| {
+            groups[0]
+                .values
+                .iter()
+                .find(|v| v.field.name == "rx_count")
+                .unwrap()
+                .total
+        };
+        assert_eq!(total(&snapshot(&registry).groups), 20);
+        // The removed device is gone, its counters are not.
+        let snap = snapshot(&registry);
+        assert_eq!(snap.groups.len(), 2);
+        assert_eq!(total(&snap.groups), 20);
+
+        eth0.rx_count.add(1);
+        let mut groups = Vec::new();
+        registry.collect_into(&mut groups, false);
+        assert_eq!(total(&groups), 21);
+    }
+
+    #[test]
+    fn test_serialization_is_not_destructive() {
+        let registry = PerDeviceMetrics::<NetDeviceMetrics>::new();
+        registry.register("eth0").unwrap().rx_bytes_count.add(7);
+
+        // Printing the metrics does not eat the delta.
+        let json = serde_json::to_value(&registry).unwrap();
+        assert_eq!(json, serde_json::to_value(&registry).unwrap());
+        assert_eq!(json["net_eth0"]["rx_bytes_count"], 7);
+
+        // A snapshot can be rendered any number of times.
+        let snap = snapshot(&registry);
+        assert_eq!(serde_json::to_value(&snap).unwrap(), serde_json::to_value(&snap).unwrap());
+        assert_eq!(serde_json::to_value(&snap).unwrap()["net_eth0"]["rx_bytes_count"], 7);
+
+        // Only taking the snapshot resets the counters.
+        let json = serde_json::to_value(snapshot(&registry)).unwrap();
+        assert_eq!(json["net_eth0"]["rx_bytes_count"], 0);
+    }
+
+    #[test]
+    fn test_serialization_matches_snapshot() {
+        let m = FirecrackerMetrics::new();
+        let eth0 = m.net.register("eth0").unwrap();
+        eth0.rx_count.add(2);
+        m.block.register("vda").unwrap().read_count.add(1);
+
+        let mut serialized = serde_json::to_value(&m).unwrap();
+        let mut snapshot = serde_json::to_value(m.snapshot()).unwrap();
+        for json in [&mut serialized, &mut snapshot] {
+            json.as_object_mut().unwrap().remove("utc_timestamp_ms");
+        }
+        assert_eq!(serialized, snapshot);
+        assert_eq!(serialized["net_eth0"]["rx_count"], 2);
+    }
+
+    #[test]
+    fn test_aggregate_matches_devices_under_load() {
+        const THREADS: usize = 4;
+        const ITERATIONS: usize = 100_000;
+
+        let registry = Arc::new(PerDeviceMetrics::<NetDeviceMetrics>::new());
+        let devices: Vec<_> = (0..THREADS)
+            .map(|i| registry.register(&format!("eth{}", i)).unwrap())
+            .collect();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let flusher = {
+            let registry = Arc::clone(&registry);
+            let done = Arc::clone(&done);
+            std::thread::spawn(move || {
+                let mut flushed = 0;
+                let mut check = |snap: MetricsSnapshot| {
+                    let agg = &snap.groups[0];
+                    for (i, field) in NetDeviceMetrics::FIELDS.iter().enumerate() {
+                        let sum: u64 = snap.groups[1..].iter().map(|g| g.values[i].value).sum();
+                        assert_eq!(agg.values[i].value, sum, "{}", field.name);
+                    }
+                    flushed += agg.values.iter().find(|v| v.field.name == "rx_count").unwrap().value;
+                };
+                while !done.load(Ordering::Acquire) {
+                    check(snapshot(&registry));
+                }
+                // Pick up whatever landed after the last loop iteration.
+                check(snapshot(&registry));
+                flushed
+            })
+        };
+
+        let writers: Vec<_> = devices
+            .into_iter()
+            .map(|dev| {
+                std::thread::spawn(move || {
+                    for _ in 0..ITERATIONS {
+                        dev.rx_count.inc();
+                        dev.tx_count.inc();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Release);
+
+        assert_eq!(flusher.join().unwrap(), (THREADS * ITERATIONS) as u64);
+    }
+
+    #[derive(Debug, Default, Clone)]
+    struct RecordingExporter(Arc<Mutex<Vec<MetricsSnapshot>>>);
+    impl MetricsExporter for RecordingExporter {
+        fn export(&mut self, snapshot: &MetricsSnapshot) -> Result<usize, MetricsError> {
+            self.0.lock().unwrap().push(snapshot.clone());
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_write_fans_out_to_exporters() {
+        let m = Metrics::<FirecrackerMetrics, Vec<u8>>::new(FirecrackerMetrics::new());
+        let first = RecordingExporter::default();
+        let second = RecordingExporter::default();
+        m.init_with_exporters(
+            Vec::new(),
+            vec![Box::new(first.clone()), Box::new(second.clone())],
+        )
+        .unwrap();
+        m.net.register("eth0").unwrap().rx_count.add(3);
+
+        assert!(m.write().unwrap());
+
+        let first = first.0.lock().unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(*first, *second.0.lock().unwrap());
+        let json = serde_json::to_value(&first[0]).unwrap();
+        assert_eq!(json["net_eth0"]["rx_count"], 3);
+        let written: serde_json::Value =
+            serde_json::from_slice(&m.metrics_buf.get().unwrap().lock().unwrap().dest.dest)
+                .unwrap();
+        assert_eq!(written, json);
+    }
+
+    #[derive(Debug)]
+    struct FailingExporter;
+    impl MetricsExporter for FailingExporter {
+        fn export(&mut self, _: &MetricsSnapshot) -> Result<usize, MetricsError> {
+            Err(MetricsError::Emf("unreachable collector".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_failed_write_is_accounted_for() {
+        let m = Metrics::<FirecrackerMetrics, Vec<u8>>::new(FirecrackerMetrics::new());
+        m.init_with_exporters(Vec::new(), vec![Box::new(FailingExporter)])
+            .unwrap();
+
+        assert!(matches!(m.write(), Err(MetricsError::Emf(_))));
+        assert_eq!(m.metrics.missed_metrics_count.fetch_diff(), 1);
+        assert_eq!(m.metrics.metrics_fails.fetch_diff(), 1);
+        assert_eq!(m.metrics.serialization_fails.fetch_diff(), 0);
+
+        // Poison the destination lock.
+        let lock = m.metrics_buf.get().unwrap();
+        let poison = || {
+            std::thread::scope(|s| {
+                s.spawn(|| {
+                    let _guard = lock.lock().unwrap();
+                    panic!("poisoning the metrics destination");
+                })
+                .join()
+                .unwrap_err();
+            })
+        };
+        poison();
+        // The poisoning is reported and counted once, along with the failure
+        // of the exporter, and the destination is still written to.
+        assert!(matches!(m.write(), Err(MetricsError::LockPoisoned)));
+        assert!(matches!(m.write(), Err(MetricsError::Emf(_))));
+        let written = String::from_utf8(m.reopen(Vec::new()).unwrap()).unwrap();
+        let fails: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .map(|json| json["metrics"]["metrics_fails"].clone())
+            .collect();
+        assert_eq!(fails, [0, 2, 1, 1]);
+
+        // `reopen` reports the poisoning before switching the destination.
+        poison();
+        assert!(matches!(m.reopen(Vec::new()), Err(MetricsError::LockPoisoned)));
+        let kept = String::from_utf8(m.reopen(Vec::new()).unwrap()).unwrap();
+        assert_eq!(kept.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_flush_health_metrics() {
+        let m = Metrics::<FirecrackerMetrics, Vec<u8>>::new(FirecrackerMetrics::new());
+        m.init(Vec::new()).unwrap();
+
+        assert!(m.write().unwrap());
+        let written = m.metrics_buf.get().unwrap().lock().unwrap().dest.dest.len();
+        assert!(written > 0);
+        assert_eq!(m.metrics.bytes_written.count(), written);
+
+        // The second flush reports the bytes written by the first one.
+        assert!(m.write().unwrap());
+        let json = serde_json::to_value(m.snapshot()).unwrap();
+        assert_eq!(json["metrics"]["missed_metrics_count"], 0);
+        assert!(json["metrics"]["flush_duration_us"].is_u64());
+        let written = m.metrics_buf.get().unwrap().lock().unwrap().dest.dest.len();
+        assert_eq!(m.metrics.bytes_written.count(), written);
+    }
+
+    #[test]
+    fn test_json_format() {
+        let compact = Metrics::<FirecrackerMetrics, Vec<u8>>::new(FirecrackerMetrics::new());
+        compact.init(Vec::new()).unwrap();
+        let pretty = Metrics::<FirecrackerMetrics, Vec<u8>>::new(FirecrackerMetrics::new());
+        let options = JsonOptions {
+            format: JsonFormat::Pretty,
+            ..Default::default()
+        };
+        pretty.init_with_options(Vec::new(), options, Vec::new()).unwrap();
+        for m in [&compact, &pretty] {
+            m.net.register("eth0").unwrap().rx_count.add(3);
+            assert!(m.write().unwrap());
+            assert!(m.write().unwrap());
+        }
+
+        // One record per line by default.
+        let compact = compact.reopen(Vec::new()).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(compact)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["net_eth0"]["rx_count"], 3);
+
+        let pretty = String::from_utf8(pretty.reopen(Vec::new()).unwrap()).unwrap();
+        assert!(pretty.lines().count() > 3);
+        let records: Vec<serde_json::Value> = serde_json::Deserializer::from_str(&pretty)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["net_eth0"]["rx_count"], 3);
+    }
+
+    #[test]
+    fn test_cumulative_temporality() {
+        let m = Metrics::<FirecrackerMetrics, Vec<u8>>::new(FirecrackerMetrics::new());
+        let options = JsonOptions {
+            temporality: Temporality::Cumulative,
+            ..Default::default()
+        };
+        m.init_with_options(Vec::new(), options, Vec::new()).unwrap();
+        let before_ns = get_time_ns(ClockType::Real);
+        let eth0 = m.net.register("eth0").unwrap();
+
+        eth0.rx_count.add(3);
+        assert!(m.write().unwrap());
+        eth0.rx_count.add(4);
+        // Losing the second line loses nothing with cumulative totals.
+        assert!(m.write().unwrap());
+        eth0.rx_count.add(5);
+        let written = String::from_utf8(m.reopen(Vec::new()).unwrap()).unwrap();
+        let totals: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .map(|json| json["net_eth0"]["rx_count"].clone())
+            .collect();
+        assert_eq!(totals, [3, 7, 12]);
+
+        let snapshot = m.snapshot();
+        let start = |name: &str| {
+            snapshot
+                .groups
+                .iter()
+                .find(|g| g.name == name)
+                .unwrap()
+                .start_time_ns
+        };
+        assert!(start("net_eth0") >= before_ns);
+        assert!(start("metrics") <= before_ns);
+        assert_eq!(start("metrics"), process_start_time_ns());
+        assert!(process_start_time_ns() > 0);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let m = Metrics::<FirecrackerMetrics, Vec<u8>>::new(FirecrackerMetrics::new());
+        assert!(matches!(
+            m.reopen(Vec::new()),
+            Err(MetricsError::NeverInitialized(_))
+        ));
+        m.init(Vec::new()).unwrap();
+        let eth0 = m.net.register("eth0").unwrap();
+
+        // Pending metrics end up in the old destination.
+        eth0.rx_count.add(3);
+        let old = m.reopen(Vec::new()).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&old).unwrap();
+        assert_eq!(json["net_eth0"]["rx_count"], 3);
+
+        eth0.rx_count.add(4);
+        let new = m.reopen(Vec::new()).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&new).unwrap();
+        assert_eq!(json["net_eth0"]["rx_count"], 4);
+    }
+
+    fn snapshot<D: DeviceMetrics>(registry: &PerDeviceMetrics<D>) -> MetricsSnapshot {
+        let mut groups = Vec::new();
+        registry.collect_into(&mut groups, true);
+        MetricsSnapshot {
+            utc_timestamp_ms: 0,
+            groups,
         }
     }
 }
@@ -0,0 +1,260 @@
+use crate::metrics::{MetricKind, Metrics, MetricsError};
+use crate::snapshot::{GroupScope, SnapshotMetrics, DEVICE_ATTRIBUTE};
+use opentelemetry_api::metrics::{
+    CallbackRegistration, Meter, MeterProvider as _, ObservableCounter, ObservableGauge, Unit,
+};
+use opentelemetry_api::KeyValue;
+use opentelemetry_sdk::metrics::MeterProvider;
+use std::any::Any;
+use std::fmt::Debug;
+use std::io::Write;
+use std::sync::Arc;
+
+/// Name of the meter the instruments are created by.
+const METER_NAME: &str = "firecracker";
+
+enum Instrument {
+    Counter(ObservableCounter<u64>),
+    Gauge(ObservableGauge<u64>),
+}
+
+impl Instrument {
+    fn as_any(&self) -> Arc<dyn Any> {
+        match self {
+            Instrument::Counter(counter) => counter.as_any(),
+            Instrument::Gauge(gauge) => gauge.as_any(),
+        }
+    }
+}
+
+/// Exposes every field of the metrics as an observable instrument named
+/// `<family>.<field>`, e.g. `net.rx_count`, on an OpenTelemetry
+/// `MeterProvider`.
+/// The instruments are read from the atomics when the provider collects, so
+/// the code incrementing the metrics does not change. Counters are observed
+/// with their cumulative value, which the provider turns into deltas if its
+/// readers ask for them, without affecting the deltas written by
+/// `Metrics::write`. Device metrics carry a `device` attribute; devices
+/// registered after the bridge show up on the next collection.
+/// The instruments are unregistered when the bridge is dropped.
+pub struct OtelBridge {
+    _meter: Meter,
+    registration: Box<dyn CallbackRegistration>,
+}
+
+impl Debug for OtelBridge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OtelBridge").finish_non_exhaustive()
+    }
+}
+
+impl OtelBridge {
+    /// Registers the instruments of `metrics` on `provider`.
+    pub fn register<T, M>(
+        metrics: &'static Metrics<T, M>,
+        provider: &MeterProvider,
+    ) -> Result<Self, MetricsError>
+    where
+        T: SnapshotMetrics + Debug + Sync,
+        M: Write + Send + Debug,
+    {
+        let meter = provider.meter(METER_NAME);
+
+        // Families and their fields are known upfront, only devices come and
+        // go. Instruments are kept in field order for every family. Families
+        // without any device yet are only seen through their aggregate.
+        let mut families: Vec<(&'static str, Vec<Instrument>)> = Vec::new();
+        for group in metrics.peek().groups {
+            if families.iter().any(|(family, _)| *family == group.family) {
+                continue;
+            }
+            let mut instruments = Vec::new();
+            for value in group.values.iter() {
+                let field = value.field;
+                let name = format!("{}.{}", group.family, field.name);
+                let description = field.description.trim();
+                let unit = Unit::new(field.unit().as_ucum());
+                let instrument = match field.kind {
+                    MetricKind::Counter => meter
+                        .u64_observable_counter(name)
+                        .with_description(description)
+                        .with_unit(unit)
+                        .try_init()
+                        .map(Instrument::Counter),
+                    MetricKind::Gauge => meter
+                        .u64_observable_gauge(name)
+                        .with_description(description)
+                        .with_unit(unit)
+                        .try_init()
+                        .map(Instrument::Gauge),
+                }
+                .map_err(|err| MetricsError::Otel(err.to_string()))?;
+                instruments.push(instrument);
+            }
+            families.push((group.family, instruments));
+        }
+
+        let handles: Vec<Arc<dyn Any>> = families
+            .iter()
+            .flat_map(|(_, instruments)| instruments.iter().map(Instrument::as_any))
+            .collect();
+        let registration = meter
+            .register_callback(&handles, move |observer| {
+                for group in metrics.peek().groups.iter() {
+                    let attributes = match &group.scope {
+                        GroupScope::Aggregate => continue,
+                        GroupScope::Single => Vec::new(),
+                        GroupScope::Device(device) => {
+                            vec![KeyValue::new(DEVICE_ATTRIBUTE, device.clone())]
+                        }
+                    };
+                    let Some((_, instruments)) =
+                        families.iter().find(|(family, _)| *family == group.family)
+                    else {
+                        continue;
+                    };
+                    for (instrument, value) in instruments.iter().zip(group.values.iter()) {
+                        match instrument {
+                            Instrument::Counter(counter) => {
+                                observer.observe_u64(counter, value.total, &attributes)
+                            }
+                            Instrument::Gauge(gauge) => {
+                                observer.observe_u64(gauge, value.total, &attributes)
+                            }
+                        }
+                    }
+                }
+            })
+            .map_err(|err| MetricsError::Otel(err.to_string()))?;
+
+        Ok(Self {
+            _meter: meter,
+            registration,
+        })
+    }
+}
+
+impl Drop for OtelBridge {
+    fn drop(&mut self) {
+        let _ = self.registration.unregister();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{leaked_metrics, IncMetric};
+    use opentelemetry_api::Context;
+    use opentelemetry_sdk::metrics::data::{ResourceMetrics, Sum, Temporality};
+    use opentelemetry_sdk::metrics::reader::{
+        AggregationSelector, MetricProducer, MetricReader, TemporalitySelector,
+    };
+    use opentelemetry_sdk::metrics::{Aggregation, InstrumentKind, ManualReader, Pipeline};
+    use opentelemetry_sdk::Resource;
+    use std::sync::Weak;
+
+    /// `ManualReader` which can be collected from after being handed over to
+    /// the provider.
+    #[derive(Debug, Clone)]
+    struct SharedReader(Arc<ManualReader>);
+    impl AggregationSelector for SharedReader {
+        fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+            self.0.aggregation(kind)
+        }
+    }
+    impl TemporalitySelector for SharedReader {
+        fn temporality(&self, kind: InstrumentKind) -> Temporality {
+            self.0.temporality(kind)
+        }
+    }
+    impl MetricReader for SharedReader {
+        fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+            self.0.register_pipeline(pipeline)
+        }
+        fn register_producer(&self, producer: Box<dyn MetricProducer>) {
+            self.0.register_producer(producer)
+        }
+        fn collect(&self, rm: &mut ResourceMetrics) -> opentelemetry_api::metrics::Result<()> {
+            self.0.collect(rm)
+        }
+        fn force_flush(&self, cx: &Context) -> opentelemetry_api::metrics::Result<()> {
+            self.0.force_flush(cx)
+        }
+        fn shutdown(&self) -> opentelemetry_api::metrics::Result<()> {
+            self.0.shutdown()
+        }
+    }
+
+    /// Collects the data points of `name` as `(device, value)` pairs.
+    fn collect(reader: &SharedReader, name: &str) -> Vec<(Option<String>, u64)> {
+        let mut rm = ResourceMetrics {
+            resource: Resource::empty(),
+            scope_metrics: Vec::new(),
+        };
+        reader.collect(&mut rm).unwrap();
+        let metric = rm
+            .scope_metrics
+            .iter()
+            .flat_map(|scope| scope.metrics.iter())
+            .find(|metric| metric.name == name)
+            .unwrap();
+        let sum = metric.data.as_any().downcast_ref::<Sum<u64>>().unwrap();
+        assert!(sum.is_monotonic);
+        let mut points: Vec<_> = sum
+            .data_points
+            .iter()
+            .map(|point| {
+                let device = point
+                    .attributes
+                    .iter()
+                    .find(|(key, _)| key.as_str() == DEVICE_ATTRIBUTE)
+                    .map(|(_, value)| value.to_string());
+                (device, point.value)
+            })
+            .collect();
+        points.sort();
+        points
+    }
+
+    #[test]
+    fn test_observable_instruments() {
+        let m = leaked_metrics::<Vec<u8>>();
+        m.init(Vec::new()).unwrap();
+        let eth0 = m.net.register("eth0").unwrap();
+        eth0.rx_bytes_count.add(10);
+
+        let reader = SharedReader(Arc::new(ManualReader::builder().build()));
+        let provider = MeterProvider::builder().with_reader(reader.clone()).build();
+        let bridge = OtelBridge::register(m, &provider).unwrap();
+
+        assert_eq!(collect(&reader, "net.rx_bytes_count"), [(Some("eth0".into()), 10)]);
+
+        // Flushing to the file does not reset what gets observed, and devices
+        // registered later get picked up.
+        assert!(m.write().unwrap());
+        eth0.rx_bytes_count.add(5);
+        m.net.register("eth1").unwrap().rx_bytes_count.add(1);
+        assert_eq!(
+            collect(&reader, "net.rx_bytes_count"),
+            [(Some("eth0".into()), 15), (Some("eth1".into()), 1)]
+        );
+        let written = String::from_utf8(m.reopen(Vec::new()).unwrap()).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_str(written.lines().next().unwrap()).unwrap();
+        assert_eq!(written["net_eth0"]["rx_bytes_count"], 10);
+
+        assert_eq!(collect(&reader, "metrics.missed_metrics_count"), [(None, 0)]);
+        drop(bridge);
+    }
+
+    #[test]
+    fn test_first_device_registered_after_bridge() {
+        let m = leaked_metrics::<Vec<u8>>();
+        let reader = SharedReader(Arc::new(ManualReader::builder().build()));
+        let provider = MeterProvider::builder().with_reader(reader.clone()).build();
+        let _bridge = OtelBridge::register(m, &provider).unwrap();
+
+        m.block.register("vda").unwrap().read_bytes.add(512);
+        assert_eq!(collect(&reader, "block.read_bytes"), [(Some("vda".into()), 512)]);
+    }
+}
@@ -0,0 +1,745 @@
+use crate::exporter::MetricsExporter;
+use crate::metrics::{process_start_time_ns, MetricKind, MetricsError};
+use crate::snapshot::{GroupScope, MetricsSnapshot, Temporality, DEVICE_ATTRIBUTE};
+use serde_json::json;
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Name of the instrumentation scope the metrics are reported under.
+const SCOPE_NAME: &str = "firecracker";
+
+/// Path OTLP/HTTP receivers accept metrics on.
+const METRICS_PATH: &str = "/v1/metrics";
+
+/// Time the receiver has to accept and answer a request.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of requests `OtlpHttpExporter` keeps while the receiver is busy.
+const QUEUE_LENGTH: usize = 4;
+
+/// Wire format of the `ExportMetricsServiceRequest`s.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OtlpEncoding {
+    /// Binary protobuf encoding, `application/x-protobuf`.
+    #[default]
+    Protobuf,
+    /// OTLP/JSON encoding, `application/json`.
+    Json,
+}
+
+impl OtlpEncoding {
+    fn content_type(&self) -> &'static str {
+        match self {
+            OtlpEncoding::Protobuf => "application/x-protobuf",
+            OtlpEncoding::Json => "application/json",
+        }
+    }
+}
+
+/// Settings of the OTLP exporters.
+#[derive(Debug, Clone)]
+pub struct OtlpConfig {
+    resource: Vec<(String, String)>,
+    encoding: OtlpEncoding,
+    temporality: Temporality,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OtlpConfig {
+    /// Creates a configuration encoding the cumulative totals in protobuf,
+    /// with `service.name` set to `firecracker`.
+    pub fn new() -> Self {
+        Self {
+            resource: vec![("service.name".to_string(), "firecracker".to_string())],
+            encoding: OtlpEncoding::Protobuf,
+            temporality: Temporality::Cumulative,
+        }
+    }
+
+    /// Sets an attribute of the resource the metrics belong to, e.g.
+    /// `service.instance.id`.
+    pub fn set_resource_attribute(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        let (key, value) = (key.into(), value.into());
+        match self.resource.iter_mut().find(|(k, _)| *k == key) {
+            Some(attribute) => attribute.1 = value,
+            None => self.resource.push((key, value)),
+        }
+        self
+    }
+
+    pub fn with_encoding(mut self, encoding: OtlpEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn with_temporality(mut self, temporality: Temporality) -> Self {
+        self.temporality = temporality;
+        self
+    }
+}
+
+/// A data point of a metric, per device for device metrics.
+#[derive(Debug)]
+struct DataPoint<'a> {
+    device: Option<&'a str>,
+    start_time_ns: u64,
+    value: u64,
+}
+
+/// A metric of the request, e.g. `net.rx_count`, with a data point per device.
+#[derive(Debug)]
+struct Metric<'a> {
+    name: String,
+    description: &'static str,
+    unit: &'static str,
+    kind: MetricKind,
+    points: Vec<DataPoint<'a>>,
+}
+
+/// Encodes `snapshot` as an OTLP `ExportMetricsServiceRequest`.
+/// Metrics are named `<family>.<field>`, e.g. `net.rx_count`, and device
+/// metrics carry a `device` attribute. Counters are monotonic sums starting at
+/// `previous_time_ns` with `Temporality::Delta`, or at the start time of their
+/// group with `Temporality::Cumulative`.
+pub fn encode_request(config: &OtlpConfig, snapshot: &MetricsSnapshot, previous_time_ns: u64) -> Vec<u8> {
+    // Metrics are grouped by name, the same way every device gets a data point.
+    let mut metrics: Vec<Metric> = Vec::new();
+    for group in snapshot.groups.iter() {
+        let device = match &group.scope {
+            GroupScope::Aggregate => continue,
+            GroupScope::Single => None,
+            GroupScope::Device(device) => Some(device.as_str()),
+        };
+        let start_time_ns = match config.temporality {
+            Temporality::Delta => previous_time_ns,
+            Temporality::Cumulative => group.start_time_ns,
+        };
+        for value in group.values.iter() {
+            let point = DataPoint {
+                device,
+                start_time_ns,
+                value: value.get(config.temporality),
+            };
+            let name = format!("{}.{}", group.family, value.field.name);
+            match metrics.iter_mut().find(|metric| metric.name == name) {
+                Some(metric) => metric.points.push(point),
+                None => metrics.push(Metric {
+                    name,
+                    description: value.field.description.trim(),
+                    unit: value.field.unit().as_ucum(),
+                    kind: value.field.kind,
+                    points: vec![point],
+                }),
+            }
+        }
+    }
+
+    let time_ns = snapshot.utc_timestamp_ms * 1_000_000;
+    match config.encoding {
+        OtlpEncoding::Protobuf => encode_protobuf(config, &metrics, time_ns),
+        OtlpEncoding::Json => encode_json(config, &metrics, time_ns).to_string().into_bytes(),
+    }
+}
+
+/// `AggregationTemporality` enum of the OTLP protocol.
+fn aggregation_temporality(temporality: Temporality) -> u64 {
+    match temporality {
+        Temporality::Delta => 1,
+        Temporality::Cumulative => 2,
+    }
+}
+
+fn encode_json(config: &OtlpConfig, metrics: &[Metric], time_ns: u64) -> serde_json::Value {
+    let key_value = |key: &str, value: &str| json!({"key": key, "value": {"stringValue": value}});
+    let metrics: Vec<serde_json::Value> = metrics
+        .iter()
+        .map(|metric| {
+            // 64 bit integers are encoded as strings in OTLP/JSON.
+            let points: Vec<serde_json::Value> = metric
+                .points
+                .iter()
+                .map(|point| {
+                    let mut json = json!({
+                        "attributes": point
+                            .device
+                            .map(|device| vec![key_value(DEVICE_ATTRIBUTE, device)])
+                            .unwrap_or_default(),
+                        "timeUnixNano": time_ns.to_string(),
+                        "asInt": point.value.to_string(),
+                    });
+                    if metric.kind == MetricKind::Counter {
+                        json["startTimeUnixNano"] = point.start_time_ns.to_string().into();
+                    }
+                    json
+                })
+                .collect();
+            let mut json = json!({
+                "name": metric.name,
+                "description": metric.description,
+                "unit": metric.unit,
+            });
+            match metric.kind {
+                MetricKind::Counter => {
+                    json["sum"] = json!({
+                        "dataPoints": points,
+                        "aggregationTemporality": aggregation_temporality(config.temporality),
+                        "isMonotonic": true,
+                    })
+                }
+                MetricKind::Gauge => json["gauge"] = json!({ "dataPoints": points }),
+            }
+            json
+        })
+        .collect();
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": config
+                    .resource
+                    .iter()
+                    .map(|(key, value)| key_value(key, value))
+                    .collect::<Vec<_>>(),
+            },
+            "scopeMetrics": [{
+                "scope": {"name": SCOPE_NAME},
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+/// Minimal protobuf writer, for the handful of types OTLP metrics use.
+#[derive(Debug, Default)]
+struct ProtoBuf(Vec<u8>);
+
+impl ProtoBuf {
+    const VARINT: u64 = 0;
+    const FIXED64: u64 = 1;
+    const LEN: u64 = 2;
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn tag(&mut self, field: u64, wire_type: u64) {
+        self.varint(field << 3 | wire_type);
+    }
+
+    fn uint(&mut self, field: u64, value: u64) {
+        self.tag(field, Self::VARINT);
+        self.varint(value);
+    }
+
+    fn fixed64(&mut self, field: u64, value: u64) {
+        self.tag(field, Self::FIXED64);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u64, value: &[u8]) {
+        self.tag(field, Self::LEN);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    fn string(&mut self, field: u64, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    /// Writes the message built by `build` as field `field`.
+    fn message(&mut self, field: u64, build: impl FnOnce(&mut ProtoBuf)) {
+        let mut message = ProtoBuf::default();
+        build(&mut message);
+        self.bytes(field, &message.0);
+    }
+
+    /// Writes a `KeyValue` holding a string as field `field`.
+    fn key_value(&mut self, field: u64, key: &str, value: &str) {
+        self.message(field, |kv| {
+            kv.string(1, key);
+            kv.message(2, |any| any.string(1, value));
+        });
+    }
+}
+
+fn encode_protobuf(config: &OtlpConfig, metrics: &[Metric], time_ns: u64) -> Vec<u8> {
+    let mut request = ProtoBuf::default();
+    // ExportMetricsServiceRequest.resource_metrics
+    request.message(1, |resource_metrics| {
+        // ResourceMetrics.resource
+        resource_metrics.message(1, |resource| {
+            for (key, value) in config.resource.iter() {
+                resource.key_value(1, key, value);
+            }
+        });
+        // ResourceMetrics.scope_metrics
+        resource_metrics.message(2, |scope_metrics| {
+            scope_metrics.message(1, |scope| scope.string(1, SCOPE_NAME));
+            for metric in metrics.iter() {
+                scope_metrics.message(2, |m| encode_protobuf_metric(config, m, metric, time_ns));
+            }
+        });
+    });
+    request.0
+}
+
+fn encode_protobuf_metric(config: &OtlpConfig, buf: &mut ProtoBuf, metric: &Metric, time_ns: u64) {
+    buf.string(1, &metric.name);
+    buf.string(2, metric.description);
+    buf.string(3, metric.unit);
+    let points = |data: &mut ProtoBuf| {
+        for point in metric.points.iter() {
+            // NumberDataPoint
+            data.message(1, |p| {
+                if metric.kind == MetricKind::Counter {
+                    p.fixed64(2, point.start_time_ns);
+                }
+                p.fixed64(3, time_ns);
+                // as_int is an sfixed64.
+                p.fixed64(6, point.value);
+                if let Some(device) = point.device {
+                    p.key_value(7, DEVICE_ATTRIBUTE, device);
+                }
+            });
+        }
+    };
+    match metric.kind {
+        // Metric.sum
+        MetricKind::Counter => buf.message(7, |sum| {
+            points(sum);
+            sum.uint(2, aggregation_temporality(config.temporality));
+            sum.uint(3, 1);
+        }),
+        // Metric.gauge
+        MetricKind::Gauge => buf.message(5, points),
+    }
+}
+
+/// Writes every flush as an `ExportMetricsServiceRequest` to `dest`, e.g. a
+/// file: one request per line with `OtlpEncoding::Json`, each request
+/// prefixed with its varint encoded length with `OtlpEncoding::Protobuf`.
+#[derive(Debug)]
+pub struct OtlpFileExporter<W> {
+    dest: W,
+    config: OtlpConfig,
+    previous_time_ns: u64,
+}
+
+impl<W: Write + Send + Debug> OtlpFileExporter<W> {
+    pub fn new(dest: W, config: OtlpConfig) -> Self {
+        Self {
+            dest,
+            config,
+            previous_time_ns: process_start_time_ns(),
+        }
+    }
+}
+
+impl<W: Write + Send + Debug> MetricsExporter for OtlpFileExporter<W> {
+    fn export(&mut self, snapshot: &MetricsSnapshot) -> Result<usize, MetricsError> {
+        let request = encode_request(&self.config, snapshot, self.previous_time_ns);
+        self.previous_time_ns = snapshot.utc_timestamp_ms * 1_000_000;
+        let mut buf = ProtoBuf::default();
+        match self.config.encoding {
+            OtlpEncoding::Protobuf => {
+                buf.varint(request.len() as u64);
+                buf.0.extend_from_slice(&request);
+            }
+            OtlpEncoding::Json => {
+                buf.0 = request;
+                buf.0.push(b'\n');
+            }
+        }
+        self.dest
+            .write_all(&buf.0)
+            .and_then(|_| self.dest.flush())
+            .map_err(|err| MetricsError::Otel(err.to_string()))
+            .map(|_| buf.0.len())
+    }
+}
+
+/// Address of a local OTLP/HTTP receiver.
+#[derive(Debug, Clone)]
+pub enum OtlpEndpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Posts every flush as an `ExportMetricsServiceRequest` to the `/v1/metrics`
+/// path of a local OTLP/HTTP receiver, on a new connection every time.
+/// Requests are handed over to a background thread, so that a stalled
+/// receiver does not hold up `Metrics::write` and the other sinks: `export`
+/// only fails if `QUEUE_LENGTH` requests are already waiting, dropping the new
+/// one, or with the failure of a previous request. The thread sends what is
+/// left and stops when the exporter is dropped.
+#[derive(Debug)]
+pub struct OtlpHttpExporter {
+    config: OtlpConfig,
+    previous_time_ns: u64,
+    requests: Option<SyncSender<Vec<u8>>>,
+    /// Failure of the last request sent, reported by the next `export`.
+    failure: Arc<Mutex<Option<String>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl OtlpHttpExporter {
+    pub fn new(endpoint: OtlpEndpoint, config: OtlpConfig) -> std::io::Result<Self> {
+        let (requests, queue) = std::sync::mpsc::sync_channel::<Vec<u8>>(QUEUE_LENGTH);
+        let failure = Arc::new(Mutex::new(None));
+        let thread_failure = Arc::clone(&failure);
+        let content_type = config.encoding.content_type();
+        let thread = std::thread::Builder::new()
+            .name("otlp-http".to_string())
+            .spawn(move || {
+                for request in queue {
+                    let status = match post(&endpoint, content_type, &request) {
+                        Ok(status_line) => match status_line.split(' ').nth(1) {
+                            Some(status) if status.starts_with('2') => None,
+                            _ => Some(format!("receiver answered \"{status_line}\"")),
+                        },
+                        Err(err) => Some(err.to_string()),
+                    };
+                    if status.is_some() {
+                        *thread_failure.lock().unwrap_or_else(PoisonError::into_inner) = status;
+                    }
+                }
+            })?;
+        Ok(Self {
+            config,
+            previous_time_ns: process_start_time_ns(),
+            requests: Some(requests),
+            failure,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// Sends `body` to `endpoint` and returns the status line of the response.
+fn post(endpoint: &OtlpEndpoint, content_type: &str, body: &[u8]) -> std::io::Result<String> {
+    let head = format!(
+        "POST {METRICS_PATH} HTTP/1.1\r\nHost: localhost\r\nContent-Type: {content_type}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    let mut response = Vec::new();
+    match endpoint {
+        OtlpEndpoint::Tcp(addr) => {
+            let mut stream = TcpStream::connect_timeout(addr, HTTP_TIMEOUT)?;
+            stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+            stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+            stream.write_all(head.as_bytes())?;
+            stream.write_all(body)?;
+            stream.read_to_end(&mut response)?;
+        }
+        OtlpEndpoint::Unix(path) => {
+            // There is no connect timeout for Unix sockets, a receiver not
+            // accepting connections only holds up the sending thread.
+            let mut stream = UnixStream::connect(path)?;
+            stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+            stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+            stream.write_all(head.as_bytes())?;
+            stream.write_all(body)?;
+            stream.read_to_end(&mut response)?;
+        }
+    }
+    let response = String::from_utf8_lossy(&response);
+    Ok(response.lines().next().unwrap_or_default().to_string())
+}
+
+impl MetricsExporter for OtlpHttpExporter {
+    fn export(&mut self, snapshot: &MetricsSnapshot) -> Result<usize, MetricsError> {
+        let request = encode_request(&self.config, snapshot, self.previous_time_ns);
+        self.previous_time_ns = snapshot.utc_timestamp_ms * 1_000_000;
+        let len = request.len();
+        // Taken before queuing, so that it cannot be the failure of this request.
+        let failure = self.failure.lock().unwrap_or_else(PoisonError::into_inner).take();
+        let queued = match self.requests.as_ref().map(|requests| requests.try_send(request)) {
+            Some(Ok(())) => Ok(len),
+            Some(Err(TrySendError::Full(_))) => Err(MetricsError::Otel(
+                "too many requests waiting for the receiver".to_string(),
+            )),
+            Some(Err(TrySendError::Disconnected(_))) | None => {
+                Err(MetricsError::Otel("sending thread is gone".to_string()))
+            }
+        };
+        match failure {
+            Some(failure) => Err(MetricsError::Otel(failure)),
+            None => queued,
+        }
+    }
+}
+
+impl Drop for OtlpHttpExporter {
+    fn drop(&mut self) {
+        // Closing the queue stops the thread once it is drained.
+        self.requests.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{FirecrackerMetrics, IncMetric};
+    use crate::snapshot::SnapshotMetrics;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// Value of a protobuf field, as far as OTLP metrics are concerned.
+    #[derive(Debug, Clone, PartialEq)]
+    enum Field {
+        Varint(u64),
+        Fixed64(u64),
+        Bytes(Vec<u8>),
+    }
+
+    fn read_varint(buf: &[u8], pos: &mut usize) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = buf[*pos];
+            *pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte < 0x80 {
+                return value;
+            }
+        }
+    }
+
+    /// Decodes the fields of a protobuf message.
+    fn decode(buf: &[u8]) -> Vec<(u64, Field)> {
+        let mut pos = 0;
+        let mut fields = Vec::new();
+        while pos < buf.len() {
+            let tag = read_varint(buf, &mut pos);
+            let field = match tag & 7 {
+                0 => Field::Varint(read_varint(buf, &mut pos)),
+                1 => {
+                    pos += 8;
+                    Field::Fixed64(u64::from_le_bytes(buf[pos - 8..pos].try_into().unwrap()))
+                }
+                2 => {
+                    let len = read_varint(buf, &mut pos) as usize;
+                    pos += len;
+                    Field::Bytes(buf[pos - len..pos].to_vec())
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            fields.push((tag >> 3, field));
+        }
+        fields
+    }
+
+    /// Every occurrence of field `number` of `message`.
+    fn get(message: &[(u64, Field)], number: u64) -> Vec<Field> {
+        message
+            .iter()
+            .filter(|(n, _)| *n == number)
+            .map(|(_, field)| field.clone())
+            .collect()
+    }
+
+    fn bytes(field: &Field) -> &[u8] {
+        match field {
+            Field::Bytes(bytes) => bytes,
+            field => panic!("{field:?} is not a length delimited field"),
+        }
+    }
+
+    /// Returns the `Metric` messages of an `ExportMetricsServiceRequest`.
+    fn decode_metrics(request: &[u8]) -> Vec<Vec<(u64, Field)>> {
+        let request = decode(request);
+        let resource_metrics = decode(bytes(&get(&request, 1)[0]));
+        let scope_metrics = decode(bytes(&get(&resource_metrics, 2)[0]));
+        get(&scope_metrics, 2)
+            .iter()
+            .map(|metric| decode(bytes(metric)))
+            .collect()
+    }
+
+    fn find_metric<'a>(metrics: &'a [Vec<(u64, Field)>], name: &str) -> &'a [(u64, Field)] {
+        metrics
+            .iter()
+            .find(|metric| bytes(&get(metric, 1)[0]) == name.as_bytes())
+            .unwrap()
+    }
+
+    fn sample_snapshot() -> MetricsSnapshot {
+        let metrics = FirecrackerMetrics::new();
+        let eth0 = metrics.net.register("eth0").unwrap();
+        eth0.rx_bytes_count.add(10);
+        let _ = metrics.snapshot();
+        eth0.rx_bytes_count.add(5);
+        metrics.net.register("eth1").unwrap();
+        metrics.snapshot()
+    }
+
+    #[test]
+    fn test_encode_protobuf() {
+        let snapshot = sample_snapshot();
+        let request = encode_request(&OtlpConfig::new(), &snapshot, 0);
+        let metrics = decode_metrics(&request);
+
+        let rx_bytes = find_metric(&metrics, "net.rx_bytes_count");
+        assert_eq!(get(rx_bytes, 3), [Field::Bytes(b"By".to_vec())]);
+        let sum = decode(bytes(&get(rx_bytes, 7)[0]));
+        assert_eq!(get(&sum, 2), [Field::Varint(2)]);
+        assert_eq!(get(&sum, 3), [Field::Varint(1)]);
+        let points = get(&sum, 1);
+        assert_eq!(points.len(), 2);
+        let eth0 = decode(bytes(&points[0]));
+        assert_eq!(get(&eth0, 6), [Field::Fixed64(15)]);
+        assert_eq!(
+            get(&eth0, 3),
+            [Field::Fixed64(snapshot.utc_timestamp_ms * 1_000_000)]
+        );
+        let device = decode(bytes(&get(&eth0, 7)[0]));
+        assert_eq!(get(&device, 1), [Field::Bytes(b"device".to_vec())]);
+        assert_eq!(
+            get(&decode(bytes(&get(&device, 2)[0])), 1),
+            [Field::Bytes(b"eth0".to_vec())]
+        );
+        let start = snapshot.groups.iter().find(|g| g.name == "net_eth0").unwrap();
+        assert_eq!(get(&eth0, 2), [Field::Fixed64(start.start_time_ns)]);
+
+        // Gauges have no start time.
+        let flush_duration = find_metric(&metrics, "metrics.flush_duration_us");
+        let gauge = decode(bytes(&get(flush_duration, 5)[0]));
+        let point = decode(bytes(&get(&gauge, 1)[0]));
+        assert!(get(&point, 2).is_empty());
+
+        // With delta temporality, counters start at the previous export.
+        let config = OtlpConfig::new().with_temporality(Temporality::Delta);
+        let metrics = decode_metrics(&encode_request(&config, &snapshot, 42));
+        let sum = decode(bytes(&get(find_metric(&metrics, "net.rx_bytes_count"), 7)[0]));
+        assert_eq!(get(&sum, 2), [Field::Varint(1)]);
+        let eth0 = decode(bytes(&get(&sum, 1)[0]));
+        assert_eq!(get(&eth0, 6), [Field::Fixed64(5)]);
+        assert_eq!(get(&eth0, 2), [Field::Fixed64(42)]);
+    }
+
+    #[test]
+    fn test_encode_json() {
+        let snapshot = sample_snapshot();
+        let config = OtlpConfig::new()
+            .with_encoding(OtlpEncoding::Json)
+            .set_resource_attribute("service.instance.id", "vm-1");
+        let mut exporter = OtlpFileExporter::new(Vec::new(), config);
+        exporter.export(&snapshot).unwrap();
+        exporter.export(&snapshot).unwrap();
+
+        let out = String::from_utf8(exporter.dest).unwrap();
+        assert_eq!(out.lines().count(), 2);
+        let json: serde_json::Value = serde_json::from_str(out.lines().next().unwrap()).unwrap();
+        let resource = &json["resourceMetrics"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][1],
+            json!({"key": "service.instance.id", "value": {"stringValue": "vm-1"}})
+        );
+        let metrics = resource["scopeMetrics"][0]["metrics"].as_array().unwrap();
+        let rx_bytes = metrics
+            .iter()
+            .find(|m| m["name"] == "net.rx_bytes_count")
+            .unwrap();
+        assert_eq!(rx_bytes["sum"]["aggregationTemporality"], 2);
+        assert_eq!(rx_bytes["sum"]["isMonotonic"], true);
+        let point = &rx_bytes["sum"]["dataPoints"][0];
+        assert_eq!(point["asInt"], "15");
+        assert_eq!(point["attributes"][0]["value"]["stringValue"], "eth0");
+        assert!(point["startTimeUnixNano"].as_str().unwrap().parse::<u64>().is_ok());
+    }
+
+    /// Stand-in OTLP/HTTP receiver answering `status` to a single request,
+    /// and handing over the request head and body.
+    fn receiver(status: &'static str) -> (SocketAddr, mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            let (head, body) = loop {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+                let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+                    continue;
+                };
+                let head = String::from_utf8(request[..end].to_vec()).unwrap();
+                let len: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if request.len() >= end + 4 + len {
+                    break (head, request[end + 4..end + 4 + len].to_vec());
+                }
+            };
+            write!(stream, "HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").unwrap();
+            tx.send((head, body)).unwrap();
+        });
+        (addr, rx)
+    }
+
+    #[test]
+    fn test_http_exporter() {
+        let snapshot = sample_snapshot();
+        let (addr, requests) = receiver("200 OK");
+        let mut exporter =
+            OtlpHttpExporter::new(OtlpEndpoint::Tcp(addr), OtlpConfig::new()).unwrap();
+        let written = exporter.export(&snapshot).unwrap();
+
+        let (head, body) = requests.recv().unwrap();
+        assert!(head.starts_with("POST /v1/metrics HTTP/1.1\r\n"), "{head}");
+        assert!(head.contains("Content-Type: application/x-protobuf\r\n"));
+        assert_eq!(body.len(), written);
+        assert_eq!(body, encode_request(&OtlpConfig::new(), &snapshot, process_start_time_ns()));
+        assert!(!decode_metrics(&body).is_empty());
+
+        // The rejection is reported by the next export, once the request went
+        // through.
+        let (addr, requests) = receiver("503 Service Unavailable");
+        let mut exporter =
+            OtlpHttpExporter::new(OtlpEndpoint::Tcp(addr), OtlpConfig::new()).unwrap();
+        exporter.export(&snapshot).unwrap();
+        requests.recv().unwrap();
+        drop(exporter.requests.take());
+        exporter.thread.take().unwrap().join().unwrap();
+        assert!(matches!(exporter.export(&snapshot), Err(MetricsError::Otel(_))));
+    }
+
+    #[test]
+    fn test_http_exporter_does_not_block() {
+        // The receiver never answers, so requests pile up.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut exporter =
+            OtlpHttpExporter::new(OtlpEndpoint::Tcp(addr), OtlpConfig::new()).unwrap();
+        let snapshot = sample_snapshot();
+        let results: Vec<_> = (0..QUEUE_LENGTH + 2).map(|_| exporter.export(&snapshot)).collect();
+        assert!(results[0].is_ok());
+        // Either the queue fills up, or a request timed out in between.
+        assert!(results.iter().any(|r| matches!(r, Err(MetricsError::Otel(_)))));
+        // Dropping the exporter would wait for the stalled requests to time out.
+        std::mem::forget(exporter);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::metrics::MetricKind;
+use crate::snapshot::{GroupScope, GroupSnapshot, MetricsSnapshot, DEVICE_ATTRIBUTE};
+use std::fmt::Write;
+
+/// Prefix of every Prometheus metric name.
+const NAMESPACE: &str = "firecracker";
+
+/// Content type of the Prometheus text exposition format rendered by `render`.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Renders `snapshot` in the Prometheus text exposition format 0.0.4.
+///
+/// Every field of a group becomes a metric family named
+/// `firecracker_<family>_<field>`, with the `_total` suffix for counters,
+/// which are exposed with their cumulative value. The entries of a
+/// `PerDeviceMetrics` share their family and are told apart by a `device`
+/// label; their aggregate is left out since it can be computed with `sum`.
+pub fn render(snapshot: &MetricsSnapshot) -> String {
+    // Groups of the same family are rendered together, in the order the
+    // families first appear in.
+    let mut families: Vec<(&str, Vec<&GroupSnapshot>)> = Vec::new();
+    for group in snapshot.groups.iter() {
+        if group.scope == GroupScope::Aggregate {
+            continue;
+        }
+        match families.iter_mut().find(|(family, _)| *family == group.family) {
+            Some((_, groups)) => groups.push(group),
+            None => families.push((group.family, vec![group])),
+        }
+    }
+
+    let mut out = String::new();
+    for (family, groups) in families {
+        for (i, field) in groups[0].values.iter().map(|v| v.field).enumerate() {
+            let (suffix, kind) = match field.kind {
+                MetricKind::Counter => ("_total", "counter"),
+                MetricKind::Gauge => ("", "gauge"),
+            };
+            let name = format!("{NAMESPACE}_{family}_{}{suffix}", field.name);
+            let help = escape(field.description.trim(), false);
+            // Writing to a `String` can not fail.
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for group in groups.iter() {
+                let Some(value) = group.values.get(i) else {
+                    continue;
+                };
+                match &group.scope {
+                    GroupScope::Device(device) => {
+                        let device = escape(device, true);
+                        let _ = writeln!(out, "{name}{{{DEVICE_ATTRIBUTE}=\"{device}\"}} {}", value.total);
+                    }
+                    _ => {
+                        let _ = writeln!(out, "{name} {}", value.total);
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Escapes HELP texts and, with `quotes`, label values.
+fn escape(text: &str, quotes: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' if quotes => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdevice::BlockDeviceMetrics;
+    use crate::metrics::{MetricField, MetricGroup, MetricsSystemMetrics, SignalMetrics};
+    use crate::netdevice::NetDeviceMetrics;
+    use crate::metrics::IncMetric;
+    use crate::snapshot::{MetricValue, SnapshotMetrics};
+
+    /// Rebuilds the snapshot of `testdata/metrics.json`, a first flush which
+    /// holds the cumulative values as well. Unlike `metrics.json`, the
+    /// fixture is not rewritten by `cargo run`.
+    fn sample_snapshot() -> MetricsSnapshot {
+        let line = include_str!("../testdata/metrics.json").trim_end();
+        let json: serde_json::Value = serde_json::from_str(line).unwrap();
+        let json = json.as_object().unwrap();
+
+        let families: [(&'static str, &'static [MetricField], bool); 4] = [
+            ("metrics", MetricsSystemMetrics::FIELDS, false),
+            ("signals", SignalMetrics::FIELDS, false),
+            ("net", NetDeviceMetrics::FIELDS, true),
+            ("block", BlockDeviceMetrics::FIELDS, true),
+        ];
+        let mut groups = Vec::new();
+        for (family, fields, per_device) in families {
+            let mut names: Vec<&String> = json
+                .keys()
+                .filter(|key| *key == family || key.starts_with(&format!("{family}_")))
+                .collect();
+            names.sort();
+            for name in names {
+                let scope = match name.strip_prefix(&format!("{family}_")) {
+                    Some(device) => GroupScope::Device(device.to_string()),
+                    None if per_device => GroupScope::Aggregate,
+                    None => GroupScope::Single,
+                };
+                let values = fields
+                    .iter()
+                    .map(|field| {
+                        let value = json[name][field.name].as_u64().unwrap();
+                        MetricValue {
+                            field,
+                            value,
+                            total: value,
+                        }
+                    })
+                    .collect();
+                groups.push(GroupSnapshot {
+                    name: name.clone(),
+                    family,
+                    scope,
+                    start_time_ns: 0,
+                    values,
+                });
+            }
+        }
+        MetricsSnapshot {
+            utc_timestamp_ms: json["utc_timestamp_ms"].as_u64().unwrap(),
+            groups,
+        }
+    }
+
+    #[test]
+    fn test_render_golden() {
+        assert_eq!(render(&sample_snapshot()), include_str!("../metrics.prom"));
+    }
+
+    #[test]
+    fn test_counters_are_cumulative() {
+        let m = crate::metrics::FirecrackerMetrics::new();
+        let eth0 = m.net.register("").unwrap();
+        eth0.rx_count.add(3);
+        let _ = m.snapshot();
+        eth0.rx_count.add(4);
+
+        let rendered = render(&m.snapshot());
+        assert!(rendered.contains("firecracker_net_rx_count_total{device=\"net0\"} 7\n"));
+        assert!(!rendered.contains("firecracker_net_rx_count_total 7"));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\\b\n\"c\"", false), "a\\\\b\\n\"c\"");
+        assert_eq!(escape("a\\b\n\"c\"", true), "a\\\\b\\n\\\"c\\\"");
+    }
+}
@@ -0,0 +1,286 @@
+use crate::metrics::{get_time_ns, ClockType};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Time to wait before rotating again after a failed rotation, so that a
+/// lasting error (e.g. a full disk) is not hit again on every line.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// When and how `RotatingFileWriter` rotates the metrics file.
+#[derive(Debug, Clone)]
+pub struct RotationPolicy {
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    keep: usize,
+    gzip: bool,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RotationPolicy {
+    /// Creates a policy which never rotates and keeps 5 rotated files.
+    pub fn new() -> Self {
+        Self {
+            max_size: None,
+            max_age: None,
+            keep: 5,
+            gzip: false,
+        }
+    }
+
+    /// Rotates once the file reaches `bytes`.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Rotates once the file has been written to for `age`.
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// Number of rotated files kept, named `<path>.1` (the most recent) to
+    /// `<path>.<keep>`. With 0, the file is truncated on rotation.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Compresses the rotated files, which are then named `<path>.<n>.gz`.
+    pub fn gzip(mut self) -> Self {
+        self.gzip = true;
+        self
+    }
+}
+
+/// File writer which rotates the file according to a `RotationPolicy`.
+/// Rotation only happens between two writes ending with a newline, so a
+/// metrics record is never split across files, and the file is renamed before
+/// its replacement gets created so no line is lost in between. Lines keep
+/// being appended to the current file while rotating fails, without
+/// touching the rotated files.
+#[derive(Debug)]
+pub struct RotatingFileWriter {
+    path: PathBuf,
+    policy: RotationPolicy,
+    file: File,
+    /// Size of the current file.
+    size: u64,
+    /// `ClockType::Monotonic` time at which the current file was opened.
+    opened_ns: u64,
+    /// Whether the last write ended with a newline.
+    at_line_start: bool,
+    /// `ClockType::Monotonic` time before which no rotation is attempted,
+    /// set after a failed one.
+    retry_after_ns: u64,
+}
+
+impl RotatingFileWriter {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn new(path: impl Into<PathBuf>, policy: RotationPolicy) -> std::io::Result<Self> {
+        let path = path.into();
+        let file = Self::open(&path)?;
+        Ok(Self {
+            size: file.metadata()?.len(),
+            file,
+            path,
+            policy,
+            opened_ns: get_time_ns(ClockType::Monotonic),
+            at_line_start: true,
+            retry_after_ns: 0,
+        })
+    }
+
+    fn open(path: &Path) -> std::io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    /// Path of the `index`th rotated file.
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        if self.policy.gzip {
+            path.push(".gz");
+        }
+        path.into()
+    }
+
+    /// Path the archive is compressed to before it replaces `<path>.1.gz`.
+    fn pending_archive_path(&self) -> PathBuf {
+        let mut path = self.rotated_path(1).into_os_string();
+        path.push(".tmp");
+        path.into()
+    }
+
+    fn should_rotate(&self) -> bool {
+        if self.size == 0 || get_time_ns(ClockType::Monotonic) < self.retry_after_ns {
+            return false;
+        }
+        let too_big = self.policy.max_size.is_some_and(|max| self.size >= max);
+        let too_old = self.policy.max_age.is_some_and(|max| {
+            get_time_ns(ClockType::Monotonic).saturating_sub(self.opened_ns) >= max.as_nanos() as u64
+        });
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.policy.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            // The archive is complete before any existing one gets shifted, so
+            // a failure leaves the previous archives untouched.
+            let pending = self.pending_archive_path();
+            if self.policy.gzip {
+                let compressed = File::create(&pending).and_then(|file| {
+                    let mut encoder = GzEncoder::new(file, Compression::default());
+                    std::io::copy(&mut File::open(&self.path)?, &mut encoder)?;
+                    encoder.finish()?.sync_all()
+                });
+                if let Err(err) = compressed {
+                    let _ = std::fs::remove_file(&pending);
+                    return Err(err);
+                }
+            }
+            for index in (1..self.policy.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            if self.policy.gzip {
+                std::fs::rename(&pending, self.rotated_path(1))?;
+                std::fs::remove_file(&self.path)?;
+            } else {
+                std::fs::rename(&self.path, self.rotated_path(1))?;
+            }
+        }
+        self.file = Self::open(&self.path)?;
+        self.size = 0;
+        self.opened_ns = get_time_ns(ClockType::Monotonic);
+        Ok(())
+    }
+}
+
+impl Write for RotatingFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // The line still goes to the current file if rotating fails, which is
+        // retried after `RETRY_INTERVAL`.
+        if self.at_line_start && self.should_rotate() && self.rotate().is_err() {
+            self.retry_after_ns = get_time_ns(ClockType::Monotonic) + RETRY_INTERVAL.as_nanos() as u64;
+        }
+        // The whole buffer is written at once so that a line is never split by
+        // a rotation.
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        if let Some(last) = buf.last() {
+            self.at_line_start = *last == b'\n';
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fc_metrics_{}_{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = test_dir("size");
+        let path = dir.join("metrics.json");
+        let mut writer =
+            RotatingFileWriter::new(&path, RotationPolicy::new().max_size(10).keep(1)).unwrap();
+
+        for line in ["line 1\n", "line 2\n", "partial ", "line 3\n", "line 4\n"] {
+            writer.write_all(line.as_bytes()).unwrap();
+        }
+
+        // The oldest lines got dropped since only 1 rotated file is kept, and
+        // the partial line was not split.
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "line 4\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("metrics.json.1")).unwrap(),
+            "partial line 3\n"
+        );
+        assert!(!dir.join("metrics.json.2").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_by_age_with_gzip() {
+        let dir = test_dir("age");
+        let path = dir.join("metrics.json");
+        let policy = RotationPolicy::new()
+            .max_age(Duration::from_millis(20))
+            .gzip();
+        let mut writer = RotatingFileWriter::new(&path, policy).unwrap();
+
+        writer.write_all(b"line 1\n").unwrap();
+        writer.write_all(b"line 2\n").unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        writer.write_all(b"line 3\n").unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "line 3\n");
+        let mut rotated = String::new();
+        GzDecoder::new(File::open(dir.join("metrics.json.1.gz")).unwrap())
+            .read_to_string(&mut rotated)
+            .unwrap();
+        assert_eq!(rotated, "line 1\nline 2\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_rotation_keeps_the_line() {
+        let dir = test_dir("failed");
+        let path = dir.join("metrics.json");
+        // The archive can not be compressed where a directory stands.
+        std::fs::create_dir(dir.join("metrics.json.1.gz.tmp")).unwrap();
+        for (index, content) in [(1, "first"), (2, "second"), (3, "third")] {
+            std::fs::write(dir.join(format!("metrics.json.{index}.gz")), content).unwrap();
+        }
+        let policy = RotationPolicy::new().max_size(1).keep(3).gzip();
+        let mut writer = RotatingFileWriter::new(&path, policy).unwrap();
+
+        for line in ["line 1\n", "line 2\n", "line 3\n"] {
+            writer.write_all(line.as_bytes()).unwrap();
+        }
+        // The next attempt is put off, though retrying right away does not
+        // touch the archives either.
+        assert!(writer.retry_after_ns > get_time_ns(ClockType::Monotonic));
+        writer.retry_after_ns = 0;
+        writer.write_all(b"line 4\n").unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "line 1\nline 2\nline 3\nline 4\n"
+        );
+        for (index, content) in [(1, "first"), (2, "second"), (3, "third")] {
+            let archive = dir.join(format!("metrics.json.{index}.gz"));
+            assert_eq!(std::fs::read_to_string(archive).unwrap(), content);
+        }
+        assert!(dir.join("metrics.json.1.gz.tmp").is_dir());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+use crate::metrics::{get_time_ns, ClockType, StoreMetric, METRICS};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::OnceLock;
+
+/// Signals after which the process is not expected to survive.
+const FATAL_SIGNALS: [libc::c_int; 3] = [libc::SIGSEGV, libc::SIGBUS, libc::SIGSYS];
+
+/// Duplicate of the metrics destination's file descriptor owned by this
+/// module, -1 until the handlers get installed.
+static METRICS_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// Actions in place for `FATAL_SIGNALS` before the handlers got installed.
+static PREVIOUS_ACTIONS: OnceLock<[libc::sigaction; FATAL_SIGNALS.len()]> = OnceLock::new();
+
+/// Size of the alternate stack the handlers run on, so that they can run
+/// after a stack overflow.
+const ALT_STACK_SIZE: usize = 64 * 1024;
+
+/// Large enough for `{"utc_timestamp_ms":<u64>,"signals":{"fatal_signal":<u64>}}\n`.
+const LINE_CAPACITY: usize = 128;
+
+/// Fixed size buffer the final metrics line is rendered into, so that nothing
+/// gets allocated from the signal handler.
+struct LineBuf {
+    buf: [u8; LINE_CAPACITY],
+    len: usize,
+}
+
+impl LineBuf {
+    const fn new() -> Self {
+        Self {
+            buf: [0; LINE_CAPACITY],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let end = (self.len + bytes.len()).min(LINE_CAPACITY);
+        self.buf[self.len..end].copy_from_slice(&bytes[..end - self.len]);
+        self.len = end;
+    }
+
+    fn push_u64(&mut self, mut value: u64) {
+        let mut digits = [0u8; 20];
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        self.push(&digits[start..]);
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Renders the line written to the metrics destination on a fatal signal.
+fn fatal_signal_line(utc_timestamp_ms: u64, signum: u64) -> LineBuf {
+    let mut line = LineBuf::new();
+    line.push(b"{\"utc_timestamp_ms\":");
+    line.push_u64(utc_timestamp_ms);
+    line.push(b",\"signals\":{\"fatal_signal\":");
+    line.push_u64(signum);
+    line.push(b"}}\n");
+    line
+}
+
+/// Records `signum` and writes it to the metrics destination.
+/// Only calls async-signal-safe functions: no lock, no allocation.
+fn emergency_flush(signum: libc::c_int) {
+    METRICS.signals.fatal_signal.store(signum as usize);
+
+    let fd = METRICS_FD.load(Ordering::Acquire);
+    if fd < 0 {
+        return;
+    }
+    let line = fatal_signal_line(get_time_ns(ClockType::Real) / 1_000_000, signum as u64);
+    let mut bytes = line.as_bytes();
+    while !bytes.is_empty() {
+        // SAFETY: `bytes` is a valid buffer of `bytes.len()` bytes.
+        let written = unsafe { libc::write(fd, bytes.as_ptr().cast(), bytes.len()) };
+        if written > 0 {
+            bytes = &bytes[written as usize..];
+        } else if written == 0
+            || std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted
+        {
+            break;
+        }
+    }
+}
+
+extern "C" fn fatal_signal_handler(
+    signum: libc::c_int,
+    info: *mut libc::siginfo_t,
+    _context: *mut libc::c_void,
+) {
+    emergency_flush(signum);
+
+    // SAFETY: the kernel hands a valid `siginfo_t` to `SA_SIGINFO` handlers.
+    let si_code = unsafe { (*info).si_code };
+    // Faults raised by the kernel happen again once the handler returns, and
+    // are then handled by whatever was in place before, e.g. the stack
+    // overflow reporter of the Rust runtime. SIGSYS does not, as the
+    // offending syscall is not retried.
+    if signum != libc::SIGSYS && si_code > 0 {
+        let index = FATAL_SIGNALS.iter().position(|s| *s == signum);
+        if let Some(previous) = PREVIOUS_ACTIONS.get().zip(index).map(|(p, i)| &p[i]) {
+            // SAFETY: `previous` was returned by `sigaction` for `signum`.
+            unsafe { libc::sigaction(signum, previous, std::ptr::null_mut()) };
+        }
+        return;
+    }
+    // The handler was installed with `SA_RESETHAND`, so the re-raised signal
+    // gets the default disposition and terminates the process once the
+    // handler returns.
+    // SAFETY: `raise` is async-signal-safe.
+    unsafe { libc::raise(signum) };
+}
+
+/// Makes a duplicate of `metrics_fd` the destination of the final line,
+/// closing the previous one.
+fn set_metrics_fd(metrics_fd: RawFd) -> std::io::Result<()> {
+    // SAFETY: `fcntl` does not touch memory, an invalid fd makes it fail.
+    let fd = unsafe { libc::fcntl(metrics_fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let previous = METRICS_FD.swap(fd, Ordering::AcqRel);
+    if previous >= 0 {
+        // SAFETY: `previous` was duplicated by this function and nothing
+        // else refers to it anymore.
+        unsafe { libc::close(previous) };
+    }
+    Ok(())
+}
+
+/// Gives the calling thread an alternate signal stack, unless it already has
+/// one (e.g. set up by the Rust runtime).
+fn ensure_alt_stack() -> std::io::Result<()> {
+    // SAFETY: `stack_t` is zero-initializable and only read by `sigaltstack`.
+    unsafe {
+        let mut current: libc::stack_t = std::mem::zeroed();
+        if libc::sigaltstack(std::ptr::null(), &mut current) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if current.ss_flags & libc::SS_DISABLE == 0 {
+            return Ok(());
+        }
+        // The stack has to outlive the thread, it is never freed.
+        let stack = Box::leak(vec![0u8; ALT_STACK_SIZE.max(libc::SIGSTKSZ)].into_boxed_slice());
+        let alt_stack = libc::stack_t {
+            ss_sp: stack.as_mut_ptr().cast(),
+            ss_flags: 0,
+            ss_size: stack.len(),
+        };
+        if libc::sigaltstack(&alt_stack, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Installs handlers for SIGSEGV, SIGBUS and SIGSYS which record the signal in
+/// `METRICS.signals.fatal_signal` and write a final JSON line to `metrics_fd`
+/// before letting the signal kill the process.
+///
+/// `metrics_fd` should be the file descriptor of the destination given to
+/// `Metrics::init`, e.g. `file.as_raw_fd()`. It is duplicated, so the line
+/// keeps going to that file even once `Metrics::reopen` or a rotation closed
+/// it; call this function again with the new file descriptor to follow them.
+/// The final line is written directly to the file descriptor, so anything
+/// buffered by the regular writer may be lost.
+///
+/// The handlers run on an alternate signal stack, which is set up for the
+/// calling thread if needed, and faults are passed on to the handlers
+/// previously in place once the line is written.
+pub fn install_fatal_signal_handlers(metrics_fd: RawFd) -> std::io::Result<()> {
+    set_metrics_fd(metrics_fd)?;
+    if PREVIOUS_ACTIONS.get().is_some() {
+        return Ok(());
+    }
+    ensure_alt_stack()?;
+
+    // SAFETY: `sigaction` is zero-initializable and only read by the kernel.
+    let mut previous: [libc::sigaction; FATAL_SIGNALS.len()] = unsafe { std::mem::zeroed() };
+    for (signum, previous) in FATAL_SIGNALS.iter().zip(previous.iter_mut()) {
+        // SAFETY: `previous` is a valid `sigaction` to write to.
+        if unsafe { libc::sigaction(*signum, std::ptr::null(), previous) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    // Handlers only get installed once, after their predecessors are known.
+    if PREVIOUS_ACTIONS.set(previous).is_err() {
+        return Ok(());
+    }
+
+    for signum in FATAL_SIGNALS {
+        // SAFETY: `sigaction` is zero-initializable and `fatal_signal_handler`
+        // only calls async-signal-safe functions.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = fatal_signal_handler
+                as extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void)
+                as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_RESETHAND;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signum, &action, std::ptr::null_mut()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    #[test]
+    fn test_fatal_signal_line() {
+        let line = fatal_signal_line(u64::MAX, libc::SIGSEGV as u64);
+        let bytes = line.as_bytes();
+        assert_eq!(bytes.last(), Some(&b'\n'));
+        let json: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+        assert_eq!(json["utc_timestamp_ms"], u64::MAX);
+        assert_eq!(json["signals"]["fatal_signal"], libc::SIGSEGV);
+    }
+
+    #[test]
+    fn test_emergency_flush() {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two file descriptors.
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // SAFETY: both ends of the pipe are owned by this test.
+        let (mut reader, writer) =
+            unsafe { (std::fs::File::from_raw_fd(fds[0]), std::fs::File::from_raw_fd(fds[1])) };
+        set_metrics_fd(fds[1]).unwrap();
+        // The line still reaches the pipe once the original fd is closed.
+        drop(writer);
+
+        emergency_flush(libc::SIGBUS);
+        // SAFETY: the duplicate is owned by `METRICS_FD`, which gives it up.
+        drop(unsafe { std::fs::File::from_raw_fd(METRICS_FD.swap(-1, Ordering::AcqRel)) });
+
+        let mut written = String::new();
+        reader.read_to_string(&mut written).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&written).unwrap();
+        assert_eq!(json["signals"]["fatal_signal"], libc::SIGBUS);
+        assert_eq!(METRICS.signals.fatal_signal.fetch(), libc::SIGBUS as usize);
+    }
+}
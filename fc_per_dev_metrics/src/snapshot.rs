@@ -0,0 +1,133 @@
+use crate::metrics::{MetricField, MetricsSystemMetrics};
+use serde::{Serialize, Serializer, ser::SerializeMap};
+use std::borrow::Cow;
+
+/// Implemented by the metrics that `Metrics::write` flushes.
+pub trait SnapshotMetrics {
+    /// Takes the deltas of every counter since the previous snapshot and
+    /// resets them. This is the only operation that mutates the counters, so it
+    /// should be called exactly once per flush interval.
+    fn snapshot(&self) -> MetricsSnapshot;
+
+    /// Reads the metrics without resetting them, for readers which only need
+    /// the cumulative totals, e.g. a scrape endpoint, so that they do not
+    /// steal the deltas of the next `snapshot`.
+    fn peek(&self) -> MetricsSnapshot;
+
+    /// Metrics about the flushes themselves, updated by `Metrics::write`.
+    fn system_metrics(&self) -> Option<&MetricsSystemMetrics> {
+        None
+    }
+}
+
+/// How counters are reported by an exporter. Gauges are not affected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Temporality {
+    /// Diff since the previous flush. A flush which does not make it to its
+    /// destination is lost for good.
+    #[default]
+    Delta,
+    /// Total since the start time of the group, see
+    /// `GroupSnapshot::start_time_ns`.
+    Cumulative,
+}
+
+/// Value of a single metric at the time of the snapshot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MetricValue {
+    pub field: &'static MetricField,
+    /// Diff since the previous snapshot for counters, current value for gauges.
+    pub value: u64,
+    /// Cumulative value since the metric was created for counters, current
+    /// value for gauges.
+    pub total: u64,
+}
+
+impl MetricValue {
+    /// Value to report with `temporality`.
+    pub fn get(&self, temporality: Temporality) -> u64 {
+        match temporality {
+            Temporality::Delta => self.value,
+            Temporality::Cumulative => self.total,
+        }
+    }
+}
+
+/// Attribute, or label, holding the device id of per-device metrics in the
+/// OpenTelemetry and Prometheus outputs.
+pub const DEVICE_ATTRIBUTE: &str = "device";
+
+/// What the metrics of a group are about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupScope {
+    /// Group which is not tracked per device, e.g. `metrics`.
+    Single,
+    /// Sum of the metrics of every device of a `PerDeviceMetrics`, e.g. `net`.
+    Aggregate,
+    /// Metrics of a single device, labelled with the device id, e.g. `eth0`
+    /// for `net_eth0`.
+    Device(String),
+}
+
+/// Values of a group of metrics, e.g. the metrics of a single device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupSnapshot {
+    /// Name the group is reported under, e.g. `net` or `net_eth0`.
+    pub name: String,
+    /// Name shared by the aggregate and the devices of a `PerDeviceMetrics`,
+    /// e.g. `net` for `net_eth0`, and equal to `name` otherwise.
+    pub family: &'static str,
+    pub scope: GroupScope,
+    /// UTC time, in nanoseconds, since which the cumulative totals are
+    /// counted: the registration of the device for device groups, the start
+    /// of the process otherwise.
+    pub start_time_ns: u64,
+    pub values: Vec<MetricValue>,
+}
+
+/// Point-in-time copy of the metrics which can be rendered to any number of
+/// formats without touching the counters.
+/// Serializes to the Firecracker metrics format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+    pub utc_timestamp_ms: u64,
+    pub groups: Vec<GroupSnapshot>,
+}
+
+impl MetricsSnapshot {
+    /// Returns the snapshot with the values reported with `temporality`, so
+    /// that it can be serialized as is.
+    pub fn with_temporality(&self, temporality: Temporality) -> Cow<'_, Self> {
+        match temporality {
+            Temporality::Delta => Cow::Borrowed(self),
+            Temporality::Cumulative => {
+                let mut snapshot = self.clone();
+                for value in snapshot.groups.iter_mut().flat_map(|g| g.values.iter_mut()) {
+                    value.value = value.total;
+                }
+                Cow::Owned(snapshot)
+            }
+        }
+    }
+}
+
+impl Serialize for GroupSnapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.values.len()))?;
+        for metric in self.values.iter() {
+            map.serialize_entry(metric.field.name, &metric.value)?;
+        }
+        map.end()
+    }
+}
+
+impl Serialize for MetricsSnapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(1 + self.groups.len()))?;
+        map.serialize_entry("utc_timestamp_ms", &self.utc_timestamp_ms)?;
+        for group in self.groups.iter() {
+            map.serialize_entry(&group.name, group)?;
+        }
+        map.end()
+    }
+}